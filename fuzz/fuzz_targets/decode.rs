@@ -0,0 +1,28 @@
+#![no_main]
+// only the decoding is used from the included modules
+#![allow(dead_code)]
+
+use libfuzzer_sys::fuzz_target;
+use crate::custom_bdecode::{decode_value_with_options, from_bytes, raw_dict_value, DecodeLimits, DecodeMode, DecodeOptions};
+use crate::torrent::Torrent;
+
+#[path = "../../src/custom_bdecode.rs"]
+mod custom_bdecode;
+#[path = "../../src/custom_bencode.rs"]
+mod custom_bencode;
+#[path = "../../src/torrent.rs"]
+mod torrent;
+#[path = "../../src/sanitize.rs"]
+mod sanitize;
+
+fuzz_target!(|data: &[u8]| {
+    let strict = DecodeOptions{ mode: DecodeMode::Strict, ..Default::default() };
+    let small = DecodeOptions{ limits: DecodeLimits{ max_depth: 3, max_elements: 20, max_size: 200 }, ..Default::default() };
+    let _ = decode_value_with_options(data, DecodeOptions::default());
+    let _ = decode_value_with_options(data, strict);
+    let _ = decode_value_with_options(data, small);
+    let _ = raw_dict_value(data, b"info");
+    if let Ok(torrent) = from_bytes::<Torrent>(data) {
+        let _ = torrent.info.get_info_hash();
+    }
+});
@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+pub(crate) const DEFAULT_CACHE_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Default, Debug, Clone, PartialEq)]
+pub(crate) struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// how many times the dirty pieces were written back
+    pub flushes: u64,
+    /// writes that were made to the disk, after the adjacent pieces were merged
+    pub disk_writes: u64,
+    pub written_pieces: u64,
+}
+impl Display for CacheStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cache hits {}, misses {}, flushes {}, {} pieces written in {} disk writes",
+            self.hits, self.misses, self.flushes, self.written_pieces, self.disk_writes,
+        )
+    }
+}
+
+struct CacheEntry {
+    data: Arc<Vec<u8>>,
+    /// dirty entries are not written to the disk yet, so they can not be evicted
+    dirty: bool,
+    last_used: u64,
+}
+
+/// Adjacent dirty pieces, that are written to the disk at once
+pub(crate) struct DirtyRun {
+    pub pos: u64,
+    pub data: Vec<u8>,
+    pieces: Vec<(u64, Arc<Vec<u8>>)>,
+}
+
+/// Pieces that are waiting to be written, and the recently read pieces. Keyed by the piece position in the data
+pub(crate) struct PieceCache {
+    capacity: usize,
+    size: usize,
+    dirty_size: usize,
+    entries: BTreeMap<u64, CacheEntry>,
+    clock: u64,
+    stats: CacheStats,
+}
+impl PieceCache {
+    /// Zero capacity makes the writes go to the disk right away
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            dirty_size: 0,
+            entries: BTreeMap::new(),
+            clock: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats.clone()
+    }
+
+    pub fn get(&mut self, pos: u64, length: u32) -> Option<Arc<Vec<u8>>> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(&pos).filter(|entry| entry.data.len() == length as usize);
+        let Some(entry) = entry else {
+            self.stats.misses += 1;
+            return None;
+        };
+        entry.last_used = self.clock;
+        self.stats.hits += 1;
+        Some(entry.data.clone())
+    }
+
+    /// Returns true if the dirty pieces do not fit into the cache anymore, and should be written back
+    pub fn insert_dirty(&mut self, pos: u64, data: Vec<u8>) -> bool {
+        self.insert(pos, Arc::new(data), true);
+        self.evict();
+        self.dirty_size > self.capacity
+    }
+
+    pub fn insert_clean(&mut self, pos: u64, data: Arc<Vec<u8>>) {
+        if self.entries.get(&pos).is_some_and(|entry| entry.dirty) {
+            return;
+        }
+        self.insert(pos, data, false);
+        self.evict();
+    }
+
+    fn insert(&mut self, pos: u64, data: Arc<Vec<u8>>, dirty: bool) {
+        self.clock += 1;
+        let length = data.len();
+        let entry = CacheEntry{ data, dirty, last_used: self.clock };
+        if let Some(old) = self.entries.insert(pos, entry) {
+            self.remove_size(&old);
+        }
+        self.size += length;
+        if dirty {
+            self.dirty_size += length;
+        }
+    }
+
+    fn remove_size(&mut self, entry: &CacheEntry) {
+        self.size -= entry.data.len();
+        if entry.dirty {
+            self.dirty_size -= entry.data.len();
+        }
+    }
+
+    /// Merges the adjacent dirty pieces. The pieces stay dirty until the runs are marked as written
+    pub fn dirty_runs(&self) -> Vec<DirtyRun> {
+        let mut runs: Vec<DirtyRun> = vec![];
+        for (pos, entry) in self.entries.iter().filter(|(_, entry)| entry.dirty) {
+            match runs.last_mut() {
+                Some(run) if run.pos + run.data.len() as u64 == *pos => {
+                    run.data.extend_from_slice(&entry.data);
+                    run.pieces.push((*pos, entry.data.clone()));
+                },
+                _ => runs.push(DirtyRun {
+                    pos: *pos,
+                    data: entry.data.to_vec(),
+                    pieces: vec![(*pos, entry.data.clone())],
+                }),
+            }
+        }
+        runs
+    }
+
+    pub fn mark_written(&mut self, runs: &[DirtyRun]) {
+        self.stats.flushes += 1;
+        self.stats.disk_writes += runs.len() as u64;
+        for (pos, data) in runs.iter().flat_map(|run| &run.pieces) {
+            let Some(entry) = self.entries.get_mut(pos) else {
+                continue;
+            };
+            // the piece could have been written again while the run was being written
+            if entry.dirty && Arc::ptr_eq(&entry.data, data) {
+                entry.dirty = false;
+                self.dirty_size -= data.len();
+                self.stats.written_pieces += 1;
+            }
+        }
+        self.evict();
+    }
+
+    /// Removes the least recently used clean pieces, until the cache fits into the capacity
+    fn evict(&mut self) {
+        while self.size > self.capacity {
+            let oldest = self.entries
+                .iter()
+                .filter(|(_, entry)| !entry.dirty)
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(pos, _)| *pos);
+            let Some(pos) = oldest else {
+                break;
+            };
+            let entry = self.entries.remove(&pos).expect("entry should exist");
+            self.remove_size(&entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_coalesce_dirty_pieces() {
+        let mut cache = PieceCache::new(100);
+        assert!(!cache.insert_dirty(20, vec![2; 10]));
+        assert!(!cache.insert_dirty(0, vec![0; 10]));
+        assert!(!cache.insert_dirty(10, vec![1; 10]));
+        assert!(!cache.insert_dirty(50, vec![5; 10]));
+
+        let runs = cache.dirty_runs();
+        let runs_info = runs.iter().map(|run| (run.pos, run.data.len())).collect::<Vec<_>>();
+        assert_eq!(vec![(0, 30), (50, 10)], runs_info);
+        assert_eq!([vec![0; 10], vec![1; 10], vec![2; 10]].concat(), runs[0].data);
+
+        cache.insert_dirty(50, vec![6; 10]);
+        cache.mark_written(&runs);
+        let runs = cache.dirty_runs();
+        assert_eq!(1, runs.len(), "piece that was written again should stay dirty");
+        assert_eq!(vec![6; 10], runs[0].data);
+        let stats = cache.stats();
+        assert_eq!((1, 2, 3), (stats.flushes, stats.disk_writes, stats.written_pieces));
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut cache = PieceCache::new(20);
+        cache.insert_clean(0, Arc::new(vec![0; 10]));
+        cache.insert_clean(10, Arc::new(vec![1; 10]));
+        assert!(cache.get(0, 10).is_some());
+        assert!(cache.get(0, 5).is_none(), "entry with another length should not be returned");
+
+        cache.insert_clean(20, Arc::new(vec![2; 10]));
+        assert!(cache.get(10, 10).is_none(), "least recently used piece should be evicted");
+        assert!(cache.get(0, 10).is_some());
+
+        assert!(cache.insert_dirty(30, vec![3; 30]), "dirty pieces over the capacity should be written back");
+        cache.insert_clean(30, Arc::new(vec![4; 30]));
+        assert_eq!(Some(Arc::new(vec![3; 30])), cache.get(30, 30), "dirty piece should not be replaced or evicted");
+        assert_eq!((3, 2), (cache.stats().hits, cache.stats().misses));
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+pub(crate) const REGULAR_UNCHOKE_SLOTS: usize = 3;
+pub(crate) const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+pub(crate) const OPTIMISTIC_UNCHOKE_INTERVAL: Duration = Duration::from_secs(30);
+/// how often the shared choker checks if it is time to rechoke
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+pub(crate) trait Clock {
+    fn now(&self) -> Instant;
+}
+
+pub(crate) struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[allow(dead_code)] // downloads keep all peers choked for now, so only the seeding mode is used
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum ChokerMode {
+    /// peers that upload to us the fastest are unchoked
+    Leeching,
+    /// peers that download from us the fastest are unchoked
+    Seeding,
+}
+
+#[derive(Default)]
+struct ChokerPeer {
+    interested: bool,
+    unchoked: bool,
+    /// bytes received from the peer since the last rechoke
+    downloaded: u64,
+    /// bytes sent to the peer since the last rechoke
+    uploaded: u64,
+}
+
+/// Tit-for-tat choking algorithm.
+/// Every RECHOKE_INTERVAL the interested peers with the best rate get the regular unchoke slots,
+/// and every OPTIMISTIC_UNCHOKE_INTERVAL the optimistic unchoke moves to the next choked interested peer.
+pub(crate) struct Choker<K, C> {
+    clock: C,
+    mode: ChokerMode,
+    slots: usize,
+    peers: BTreeMap<K, ChokerPeer>,
+    optimistic: Option<K>,
+    last_rechoke: Option<Instant>,
+    last_optimistic: Option<Instant>,
+}
+impl<K: Ord + Copy, C: Clock> Choker<K, C> {
+    pub fn new(clock: C, mode: ChokerMode, slots: usize) -> Self {
+        Self {
+            clock,
+            mode,
+            slots,
+            peers: BTreeMap::new(),
+            optimistic: None,
+            last_rechoke: None,
+            last_optimistic: None,
+        }
+    }
+
+    pub fn add_peer(&mut self, key: K) {
+        self.peers.entry(key).or_default();
+    }
+
+    pub fn remove_peer(&mut self, key: &K) {
+        self.peers.remove(key);
+        if self.optimistic.as_ref() == Some(key) {
+            self.optimistic = None;
+        }
+    }
+
+    /// Returns true if the peer got unchoked right away, because there was a free regular slot
+    pub fn set_interested(&mut self, key: &K, interested: bool) -> bool {
+        let free_slot = self.regular_unchoked_count() < self.slots;
+        let Some(peer) = self.peers.get_mut(key) else {
+            return false;
+        };
+        peer.interested = interested;
+        if interested && free_slot && !peer.unchoked {
+            peer.unchoked = true;
+            return true;
+        }
+        false
+    }
+
+    #[allow(dead_code)] // only used in the leeching mode
+    pub fn record_downloaded(&mut self, key: &K, bytes: u64) {
+        if let Some(peer) = self.peers.get_mut(key) {
+            peer.downloaded += bytes;
+        }
+    }
+
+    pub fn record_uploaded(&mut self, key: &K, bytes: u64) {
+        if let Some(peer) = self.peers.get_mut(key) {
+            peer.uploaded += bytes;
+        }
+    }
+
+    pub fn is_unchoked(&self, key: &K) -> bool {
+        self.peers.get(key).is_some_and(|peer| peer.unchoked)
+    }
+
+    fn regular_unchoked_count(&self) -> usize {
+        self.peers
+            .iter()
+            .filter(|(key, peer)| peer.unchoked && self.optimistic.as_ref() != Some(*key))
+            .count()
+    }
+
+    /// Rechokes the peers if the interval has passed. Returns true if the rechoke was done
+    pub fn tick(&mut self) -> bool {
+        let now = self.clock.now();
+        if self.last_rechoke.is_some_and(|last| now.duration_since(last) < RECHOKE_INTERVAL) {
+            return false;
+        }
+        self.last_rechoke = Some(now);
+
+        // all the counters cover the same period of time, so comparing bytes is the same as comparing rates
+        let mode = self.mode;
+        let mut candidates = self.peers
+            .iter()
+            .filter(|(_, peer)| peer.interested)
+            .map(|(key, peer)| {
+                let rate = match mode {
+                    ChokerMode::Leeching => peer.downloaded,
+                    ChokerMode::Seeding => peer.uploaded,
+                };
+                (*key, rate)
+            })
+            .collect::<Vec<_>>();
+        // stable sort keeps the peers with equal rates ordered by key
+        candidates.sort_by(|(_, a), (_, b)| b.cmp(a));
+        let regular = candidates
+            .iter()
+            .take(self.slots)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        let optimistic_expired = match self.last_optimistic {
+            Some(last) => now.duration_since(last) >= OPTIMISTIC_UNCHOKE_INTERVAL,
+            None => true,
+        };
+        let optimistic_valid = self.optimistic.is_some_and(|key| {
+            !regular.contains(&key) && self.peers.get(&key).is_some_and(|peer| peer.interested)
+        });
+        if optimistic_expired || !optimistic_valid {
+            self.optimistic = self.next_optimistic(&regular);
+            self.last_optimistic = Some(now);
+        }
+
+        for (key, peer) in self.peers.iter_mut() {
+            peer.unchoked = regular.contains(key) || self.optimistic.as_ref() == Some(key);
+            peer.downloaded = 0;
+            peer.uploaded = 0;
+        }
+        true
+    }
+
+    /// Rotates through the choked interested peers in the key order
+    fn next_optimistic(&self, regular: &[K]) -> Option<K> {
+        let is_candidate = |key: &K, peer: &ChokerPeer| peer.interested && !regular.contains(key);
+        let after_current = match self.optimistic {
+            Some(current) => self.peers
+                .range(current..)
+                .find(|(key, peer)| **key != current && is_candidate(key, peer)),
+            None => None,
+        };
+        after_current
+            .or_else(|| self.peers.iter().find(|(key, peer)| is_candidate(key, peer)))
+            .map(|(key, _)| *key)
+    }
+}
+
+/// Choker that is shared between the peer connections.
+/// Connections are notified through the returned receiver when they should check if they are unchoked
+pub(crate) struct SharedChoker {
+    choker: Mutex<Choker<SocketAddr, SystemClock>>,
+    changed: watch::Sender<()>,
+}
+impl SharedChoker {
+    pub fn new(mode: ChokerMode) -> Self {
+        let (changed, _) = watch::channel(());
+        Self {
+            choker: Mutex::new(Choker::new(SystemClock, mode, REGULAR_UNCHOKE_SLOTS)),
+            changed,
+        }
+    }
+
+    pub fn add_peer(&self, key: SocketAddr) -> watch::Receiver<()> {
+        self.choker.lock().expect("poisoned lock").add_peer(key);
+        self.changed.subscribe()
+    }
+
+    pub fn remove_peer(&self, key: &SocketAddr) {
+        self.choker.lock().expect("poisoned lock").remove_peer(key);
+    }
+
+    pub fn set_interested(&self, key: &SocketAddr, interested: bool) {
+        let unchoked = self.choker.lock().expect("poisoned lock").set_interested(key, interested);
+        if unchoked {
+            self.changed.send_replace(());
+        }
+    }
+
+    pub fn record_uploaded(&self, key: &SocketAddr, bytes: u64) {
+        self.choker.lock().expect("poisoned lock").record_uploaded(key, bytes);
+    }
+
+    pub fn is_unchoked(&self, key: &SocketAddr) -> bool {
+        self.choker.lock().expect("poisoned lock").is_unchoked(key)
+    }
+
+    /// Runs the rechoking until the task is dropped
+    pub async fn run(&self) {
+        let mut interval = tokio::time::interval(TICK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let rechoked = self.choker.lock().expect("poisoned lock").tick();
+            if rechoked {
+                self.changed.send_replace(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use super::*;
+
+    #[derive(Clone)]
+    struct FakeClock {
+        now: Rc<Cell<Instant>>,
+    }
+    impl FakeClock {
+        fn new() -> Self {
+            Self { now: Rc::new(Cell::new(Instant::now())) }
+        }
+        fn advance(&self, duration: Duration) {
+            self.now.set(self.now.get() + duration);
+        }
+    }
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.now.get()
+        }
+    }
+
+    fn unchoked_peers<C: Clock>(choker: &Choker<u32, C>) -> Vec<u32> {
+        choker.peers.keys().copied().filter(|key| choker.is_unchoked(key)).collect()
+    }
+
+    fn create_choker(mode: ChokerMode, peers_count: u32) -> (Choker<u32, FakeClock>, FakeClock) {
+        let clock = FakeClock::new();
+        let mut choker = Choker::new(clock.clone(), mode, 2);
+        for key in 0..peers_count {
+            choker.add_peer(key);
+        }
+        (choker, clock)
+    }
+
+    #[test]
+    fn test_free_slots_are_used_right_away() {
+        let (mut choker, _) = create_choker(ChokerMode::Seeding, 4);
+        assert!(choker.set_interested(&0, true));
+        assert!(choker.set_interested(&1, true));
+        assert!(!choker.set_interested(&2, true), "all slots are taken");
+        assert_eq!(vec![0, 1], unchoked_peers(&choker));
+    }
+
+    #[test]
+    fn test_rechoke_by_download_rate() {
+        let (mut choker, clock) = create_choker(ChokerMode::Leeching, 5);
+        for key in 0..5 {
+            choker.set_interested(&key, true);
+        }
+        assert!(choker.tick());
+        // peers are ordered by key when the rates are equal, the next one gets the optimistic unchoke
+        assert_eq!(vec![0, 1, 2], unchoked_peers(&choker));
+
+        choker.record_downloaded(&3, 300);
+        choker.record_downloaded(&4, 200);
+        choker.record_downloaded(&0, 100);
+        choker.record_uploaded(&1, 1000);
+        clock.advance(RECHOKE_INTERVAL - Duration::from_secs(1));
+        assert!(!choker.tick(), "rechoke interval has not passed yet");
+        clock.advance(Duration::from_secs(1));
+        assert!(choker.tick());
+        // optimistic unchoke of the peer 2 is kept for 30 seconds
+        assert_eq!(vec![2, 3, 4], unchoked_peers(&choker));
+
+        choker.record_downloaded(&0, 100);
+        choker.record_downloaded(&1, 50);
+        clock.advance(RECHOKE_INTERVAL);
+        assert!(choker.tick());
+        assert_eq!(vec![0, 1, 2], unchoked_peers(&choker), "peer 2 is still unchoked optimistically");
+    }
+
+    #[test]
+    fn test_seeding_ranks_by_upload_rate() {
+        let (mut choker, clock) = create_choker(ChokerMode::Seeding, 4);
+        for key in 0..4 {
+            choker.set_interested(&key, true);
+        }
+        choker.record_uploaded(&3, 300);
+        choker.record_uploaded(&2, 200);
+        choker.record_downloaded(&0, 1000);
+        assert!(choker.tick());
+        assert_eq!(vec![0, 2, 3], unchoked_peers(&choker), "peer 0 is the optimistic unchoke");
+
+        choker.record_uploaded(&1, 300);
+        choker.record_uploaded(&3, 200);
+        choker.record_downloaded(&2, 1000);
+        clock.advance(RECHOKE_INTERVAL);
+        assert!(choker.tick());
+        assert_eq!(vec![0, 1, 3], unchoked_peers(&choker));
+    }
+
+    #[test]
+    fn test_modes_rank_by_different_rates() {
+        let unchoked_in_mode = |mode| {
+            let (mut choker, _) = create_choker(mode, 4);
+            for key in 0..4 {
+                choker.set_interested(&key, true);
+            }
+            // peers 0 and 1 upload to us, peers 2 and 3 download from us
+            choker.record_downloaded(&0, 300);
+            choker.record_downloaded(&1, 200);
+            choker.record_uploaded(&2, 300);
+            choker.record_uploaded(&3, 200);
+            assert!(choker.tick());
+            unchoked_peers(&choker)
+        };
+        assert_eq!(vec![0, 1, 2], unchoked_in_mode(ChokerMode::Leeching), "peer 2 is the optimistic unchoke");
+        assert_eq!(vec![0, 2, 3], unchoked_in_mode(ChokerMode::Seeding), "peer 0 is the optimistic unchoke");
+    }
+
+    #[test]
+    fn test_optimistic_unchoke_rotation() {
+        let (mut choker, clock) = create_choker(ChokerMode::Seeding, 5);
+        for key in 0..5 {
+            choker.set_interested(&key, true);
+        }
+        let mut optimistic = vec![];
+        for _ in 0..4 {
+            for _ in 0..3 {
+                choker.record_uploaded(&0, 100);
+                choker.record_uploaded(&1, 100);
+                assert!(choker.tick());
+                clock.advance(RECHOKE_INTERVAL);
+            }
+            optimistic.push(choker.optimistic.expect("there are choked interested peers"));
+        }
+        assert_eq!(vec![2, 3, 4, 2], optimistic);
+    }
+
+    #[test]
+    fn test_not_interested_peers_stay_choked() {
+        let (mut choker, clock) = create_choker(ChokerMode::Seeding, 4);
+        choker.set_interested(&1, true);
+        choker.record_uploaded(&0, 1000);
+        assert!(choker.tick());
+        assert_eq!(vec![1], unchoked_peers(&choker));
+
+        choker.set_interested(&1, false);
+        clock.advance(RECHOKE_INTERVAL);
+        assert!(choker.tick());
+        assert!(unchoked_peers(&choker).is_empty());
+
+        choker.set_interested(&3, true);
+        choker.remove_peer(&3);
+        clock.advance(RECHOKE_INTERVAL);
+        assert!(choker.tick());
+        assert!(unchoked_peers(&choker).is_empty());
+    }
+}
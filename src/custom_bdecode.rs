@@ -1,140 +1,960 @@
 use std::collections::BTreeMap;
-use anyhow::{bail, Context};
+use std::fmt::{Display, Formatter};
+use serde::de::{DeserializeSeed, EnumAccess, MapAccess, SeqAccess, VariantAccess, Visitor};
+use serde::Deserialize;
 use crate::custom_bencode::Value;
 
-pub(crate) fn decode_value_str(input: &str) -> anyhow::Result<Value> {
+/// bytes shown on each side of the error position
+const EXCERPT_LENGTH: usize = 8;
+
+#[cfg(test)]
+pub(crate) fn decode_value_str(input: &str) -> Result<Value<'_>, DecodeError> {
     decode_value(input.as_bytes())
 }
 
-pub(crate) fn decode_value(input: &[u8]) -> anyhow::Result<Value> {
-    let (value, tail) = decode_value_inner(input)?;
-    if tail.len() > 0 {
-        bail!("invalid format, input is not completely consumed");
+pub(crate) fn decode_value(input: &[u8]) -> Result<Value<'_>, DecodeError> {
+    decode_value_with_options(input, DecodeOptions::default())
+}
+
+pub(crate) fn decode_value_with_options(input: &[u8], options: DecodeOptions) -> Result<Value<'_>, DecodeError> {
+    let mut decoder = Decoder::new(input, options)?;
+    let value = decoder.decode_value()?;
+    if !decoder.rest().is_empty() {
+        return Err(decoder.error(DecodeErrorKind::InvalidFormat("input is not completely consumed".to_string())));
     }
     Ok(value)
 }
 
-fn decode_value_inner(input: &[u8]) -> anyhow::Result<(Value, &[u8])> {
-    let Some(&first) = input.get(0) else {
-        bail!("empty input");
-    };
-    match first {
-        b'i' => decode_int(input),
-        b'0'..=b'9' => decode_string(input),
-        b'l' => decode_list(input),
-        b'd' => decode_dict(input),
-        _ => bail!("invalid format, can't parse a value that starts with {first}"),
+/// The value at the start of the input, and the data after it. The data after the value can be a part
+/// of the same message, like the metadata piece after the ut_metadata dict
+pub(crate) enum Decoded<'a> {
+    Complete(Value<'a>, &'a [u8]),
+    /// The input ends in the middle of the value
+    Incomplete,
+}
+
+/// The size limit applies to the value, the data after it can be longer
+pub(crate) fn decode_value_partial(input: &[u8], options: DecodeOptions) -> Result<Decoded<'_>, DecodeError> {
+    let limited = &input[..input.len().min(options.limits.max_size)];
+    let mut decoder = Decoder::new(limited, options)?;
+    match decoder.decode_value() {
+        Ok(value) => {
+            let consumed = limited.len() - decoder.rest().len();
+            Ok(Decoded::Complete(value, &input[consumed..]))
+        },
+        Err(DecodeError{ kind: DecodeErrorKind::UnexpectedEnd, .. }) if limited.len() < input.len() => {
+            Err(decoder.error_at(limited.len(), DecodeErrorKind::SizeLimit(options.limits.max_size)))
+        },
+        Err(DecodeError{ kind: DecodeErrorKind::UnexpectedEnd, .. }) => Ok(Decoded::Incomplete),
+        Err(error) => Err(error),
     }
 }
 
-fn decode_string(input: &[u8]) -> anyhow::Result<(Value, &[u8])> {
-    let mut iter = input.splitn(2, |x| *x == b':');
-    let Some(length) = iter.next() else {
-        bail!("failed to get string length");
-    };
-    let Some(tail) = iter.next() else {
-        bail!("failed to get string, delimiter not found");
-    };
-    debug_assert!(iter.next().is_none());
-    let length = std::str::from_utf8(length).context("string length is not valid utf8")?;
-    let length = length.parse::<usize>().context("length is not a valid number")?;
-    let tail_len = tail.len();
-    if length > tail_len {
-        bail!("expected string length {length} is larger than remaining length {tail_len}");
+/// Buffers the input that arrives in chunks, and decodes the values one after another once they are complete
+pub(crate) struct StreamDecoder {
+    buffer: Vec<u8>,
+    /// length of the values that were already returned
+    consumed: usize,
+    options: DecodeOptions,
+}
+impl StreamDecoder {
+    pub fn new(options: DecodeOptions) -> Self {
+        Self{ buffer: vec![], consumed: 0, options }
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) {
+        self.buffer.drain(..self.consumed);
+        self.consumed = 0;
+        self.buffer.extend_from_slice(chunk);
+    }
+
+    /// Returns None if more data is needed. The size limit applies to each value separately
+    pub fn next_value(&mut self) -> Result<Option<Value<'_>>, DecodeError> {
+        let input = &self.buffer[self.consumed..];
+        if input.is_empty() {
+            return Ok(None);
+        }
+        match decode_value_partial(input, self.options)? {
+            Decoded::Complete(value, rest) => {
+                self.consumed = self.buffer.len() - rest.len();
+                Ok(Some(value))
+            },
+            Decoded::Incomplete => Ok(None),
+        }
+    }
+
+    /// Buffered data after the returned values
+    pub fn remainder(&self) -> &[u8] {
+        &self.buffer[self.consumed..]
     }
-    let (string, tail) = tail.split_at(length);
-    Ok((Value::Str(string), tail))
 }
 
-fn decode_int(input: &[u8]) -> anyhow::Result<(Value, &[u8])> {
-    let mut iter = input[1..].splitn(2, |x| *x == b'e');
-    let Some(num) = iter.next() else {
-        bail!("failed to get integer");
-    };
-    let Some(tail) = iter.next() else {
-        bail!("end of integer not found");
-    };
-    debug_assert!(iter.next().is_none());
-    let num = std::str::from_utf8(num).context("integer is not valid utf8")?;
-    let num = num.parse().context("invalid int")?;
-    Ok((Value::Int(num), tail))
-}
-
-fn decode_list(input: &[u8]) -> anyhow::Result<(Value, &[u8])> {
-    let mut input = &input[1..];
-    let mut list = vec![];
-    loop {
-        let Some(&next) = input.get(0) else {
-            bail!("invalid format, list does not have an end");
-        };
-        if next == b'e' {
-            input = &input[1..];
-            break;
+/// Returns the encoded value of the key from the top level dict, as it is in the input
+pub(crate) fn raw_dict_value<'a>(input: &'a [u8], key: &[u8]) -> Result<Option<&'a [u8]>, DecodeError> {
+    let mut decoder = Decoder::new(input, DecodeOptions::default())?;
+    decoder.enter(b'd')?;
+    while !decoder.at_end()? {
+        let current_key = decoder.decode_key()?;
+        let start = decoder.pos;
+        decoder.decode_value()?;
+        if current_key == key {
+            return Ok(Some(&input[start..decoder.pos]));
+        }
+    }
+    Ok(None)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum DecodeMode {
+    /// Integers like `i-0e`, `i03e` and `i+5e`, string lengths with leading zeros, and unsorted dict keys
+    /// are accepted. The last value is kept for the duplicate keys
+    #[default]
+    Lenient,
+    /// Only the canonical encoding is accepted, so the value is encoded back to the same bytes
+    Strict,
+}
+
+/// Protects from the inputs that would take too much memory or overflow the stack, when decoding untrusted data
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct DecodeLimits {
+    /// lists and dicts inside each other
+    pub max_depth: usize,
+    /// all values in the input, including the dict keys
+    pub max_elements: usize,
+    /// length of the input in bytes
+    pub max_size: usize,
+}
+impl Default for DecodeLimits {
+    /// Enough for the torrents with hundreds of thousands of files and tens of terabytes of data
+    fn default() -> Self {
+        Self {
+            max_depth: 64,
+            max_elements: 1 << 22,
+            max_size: 64 << 20,
         }
-        let (value, tail) = decode_value_inner(input)?;
-        input = tail;
-        list.push(value);
     }
-    Ok((Value::List(list), input))
 }
 
-fn decode_dict(input: &[u8]) -> anyhow::Result<(Value, &[u8])> {
-    let mut input = &input[1..];
-    let mut dict = BTreeMap::new();
-    loop {
-        let Some(&next) = input.get(0) else {
-            bail!("invalid format, dict does not have an end");
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct DecodeOptions {
+    pub mode: DecodeMode,
+    pub limits: DecodeLimits,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum DecodeErrorKind {
+    /// The input ends in the middle of a value
+    UnexpectedEnd,
+    InvalidFormat(String),
+    /// Only returned in the strict mode
+    NotCanonical(String),
+    /// The value does not match the type it's deserialized into
+    InvalidValue(String),
+    DepthLimit(usize),
+    ElementLimit(usize),
+    SizeLimit(usize),
+}
+impl Display for DecodeErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnexpectedEnd => f.write_str("unexpected end of input"),
+            Self::InvalidFormat(message) => write!(f, "invalid format, {message}"),
+            Self::NotCanonical(message) => write!(f, "not canonical, {message}"),
+            Self::InvalidValue(message) => f.write_str(message),
+            Self::DepthLimit(limit) => write!(f, "nesting is deeper than the limit of {limit}"),
+            Self::ElementLimit(limit) => write!(f, "input has more values than the limit of {limit}"),
+            Self::SizeLimit(limit) => write!(f, "input is larger than the limit of {limit} bytes"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct DecodeError {
+    pub kind: DecodeErrorKind,
+    /// None until the error is located, only for the errors that are made by the deserialized types
+    pub offset: Option<usize>,
+    /// Keys and list indexes from the root to the failed value, like `info.files[3].length`
+    pub path: String,
+    /// Input around the offset in hex, the offset is marked with `|`
+    pub excerpt: String,
+}
+impl DecodeError {
+    fn new(kind: DecodeErrorKind) -> Self {
+        Self{ kind, offset: None, path: String::new(), excerpt: String::new() }
+    }
+}
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(offset) = self.offset {
+            write!(f, " at byte {offset}")?;
+        }
+        if !self.path.is_empty() {
+            write!(f, " in {}", self.path)?;
+        }
+        if !self.excerpt.is_empty() {
+            write!(f, ", near {}", self.excerpt)?;
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for DecodeError {}
+impl serde::de::Error for DecodeError {
+    fn custom<T: Display>(msg: T) -> Self {
+        Self::new(DecodeErrorKind::InvalidValue(msg.to_string()))
+    }
+}
+
+enum PathSegment<'a> {
+    Key(&'a [u8]),
+    Index(usize),
+}
+
+struct Decoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+    mode: DecodeMode,
+    limits: DecodeLimits,
+    /// lists and dicts that are being decoded
+    depth: usize,
+    elements: usize,
+    /// the containers that are being decoded, only used for the errors
+    path: Vec<PathSegment<'a>>,
+}
+impl<'a> Decoder<'a> {
+    fn new(input: &'a [u8], options: DecodeOptions) -> Result<Self, DecodeError> {
+        let DecodeOptions{ mode, limits } = options;
+        let decoder = Self{ input, pos: 0, mode, limits, depth: 0, elements: 0, path: vec![] };
+        if input.len() > limits.max_size {
+            return Err(decoder.error(DecodeErrorKind::SizeLimit(limits.max_size)));
+        }
+        Ok(decoder)
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.input[self.pos..]
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn error(&self, kind: DecodeErrorKind) -> DecodeError {
+        self.error_at(self.pos, kind)
+    }
+
+    fn error_at(&self, offset: usize, kind: DecodeErrorKind) -> DecodeError {
+        self.locate(DecodeError::new(kind), offset)
+    }
+
+    /// Adds the position and the current path, if the error does not have them yet
+    fn locate(&self, mut error: DecodeError, offset: usize) -> DecodeError {
+        if error.offset.is_some() {
+            return error;
+        }
+        error.offset = Some(offset);
+        error.path = self.path_string();
+        let before = &self.input[offset.saturating_sub(EXCERPT_LENGTH)..offset];
+        let after = &self.input[offset..self.input.len().min(offset + EXCERPT_LENGTH)];
+        error.excerpt = format!("{}|{}", hex::encode(before), hex::encode(after));
+        error
+    }
+
+    fn path_string(&self) -> String {
+        let mut path = String::new();
+        for segment in &self.path {
+            match segment {
+                PathSegment::Key(key) => {
+                    if !path.is_empty() {
+                        path.push('.');
+                    }
+                    match std::str::from_utf8(key) {
+                        Ok(key) => path.push_str(key),
+                        Err(_) => path.push_str(&hex::encode(key)),
+                    }
+                },
+                PathSegment::Index(index) => path.push_str(&format!("[{index}]")),
+            }
+        }
+        path
+    }
+
+    /// Skips the expected byte
+    fn expect(&mut self, expected: u8) -> Result<(), DecodeError> {
+        match self.peek() {
+            Some(next) if next == expected => {
+                self.pos += 1;
+                Ok(())
+            },
+            Some(next) => {
+                let message = format!("expected {}, got a value that starts with {next}", expected as char);
+                Err(self.error(DecodeErrorKind::InvalidFormat(message)))
+            },
+            None => Err(self.error(DecodeErrorKind::UnexpectedEnd)),
+        }
+    }
+
+    fn count_element(&mut self) -> Result<(), DecodeError> {
+        self.elements += 1;
+        if self.elements > self.limits.max_elements {
+            return Err(self.error(DecodeErrorKind::ElementLimit(self.limits.max_elements)));
+        }
+        Ok(())
+    }
+
+    /// Skips the start of a list or a dict
+    fn enter(&mut self, start: u8) -> Result<(), DecodeError> {
+        if self.depth >= self.limits.max_depth {
+            return Err(self.error(DecodeErrorKind::DepthLimit(self.limits.max_depth)));
+        }
+        self.count_element()?;
+        self.expect(start)?;
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// Skips the end of a list or a dict, it should be checked with `at_end` first
+    fn leave(&mut self) {
+        self.depth -= 1;
+        self.pos += 1;
+    }
+
+    /// The number is cut off by the end of the input, unless there is already a byte that can't be a part of it
+    fn unterminated_number(&self, start: usize, allow_signs: bool) -> DecodeError {
+        let is_number = self.rest().iter().all(|byte| byte.is_ascii_digit() || (allow_signs && matches!(byte, b'-' | b'+')));
+        if is_number {
+            self.error_at(start, DecodeErrorKind::UnexpectedEnd)
+        } else {
+            self.error_at(start, DecodeErrorKind::InvalidFormat("number is not terminated".to_string()))
+        }
+    }
+
+    /// Checks for the end of a list or a dict
+    fn at_end(&self) -> Result<bool, DecodeError> {
+        match self.peek() {
+            Some(next) => Ok(next == b'e'),
+            None => Err(self.error(DecodeErrorKind::UnexpectedEnd)),
+        }
+    }
+
+    fn decode_value(&mut self) -> Result<Value<'a>, DecodeError> {
+        let Some(first) = self.peek() else {
+            return Err(self.error(DecodeErrorKind::UnexpectedEnd));
+        };
+        match first {
+            b'i' => self.decode_int().map(Value::Int),
+            b'0'..=b'9' => self.decode_string().map(Value::Str),
+            b'l' => self.decode_list(),
+            b'd' => self.decode_dict(),
+            _ => Err(self.error(DecodeErrorKind::InvalidFormat(format!("can't parse a value that starts with {first}")))),
+        }
+    }
+
+    /// Returns the bytes up to the delimiter, and moves the position after it
+    fn take_until(&mut self, delimiter: u8) -> Option<&'a [u8]> {
+        let rest = self.rest();
+        let length = rest.iter().position(|x| *x == delimiter)?;
+        self.pos += length + 1;
+        Some(&rest[..length])
+    }
+
+    fn decode_string(&mut self) -> Result<&'a [u8], DecodeError> {
+        self.count_element()?;
+        let start = self.pos;
+        let Some(length) = self.take_until(b':') else {
+            return Err(self.unterminated_number(start, false));
+        };
+        let length = String::from_utf8_lossy(length);
+        if self.mode == DecodeMode::Strict && !is_canonical_number(&length, false) {
+            return Err(self.error_at(start, DecodeErrorKind::NotCanonical(format!("string length {length}"))));
+        }
+        let Ok(length) = length.parse::<usize>() else {
+            return Err(self.error_at(start, DecodeErrorKind::InvalidFormat(format!("invalid string length {length}"))));
         };
-        if next == b'e' {
-            input = &input[1..];
-            break;
-        }
-        let (key, tail) = decode_value_inner(input)?;
-        input = tail;
-        let Value::Str(key) = key else {
-            bail!("invalid format, dict key is not a string");
+        if length > self.rest().len() {
+            return Err(self.error_at(start, DecodeErrorKind::UnexpectedEnd));
+        }
+        let string = &self.rest()[..length];
+        self.pos += length;
+        Ok(string)
+    }
+
+    fn decode_int(&mut self) -> Result<i64, DecodeError> {
+        self.count_element()?;
+        let start = self.pos;
+        self.expect(b'i')?;
+        let Some(num) = self.take_until(b'e') else {
+            return Err(self.unterminated_number(start, true));
         };
-        let key = std::str::from_utf8(key).context("dict key is not a valid utf8")?;
-        let (value, tail) = decode_value_inner(input)?;
-        input = tail;
-        dict.insert(key, value);
+        let num = String::from_utf8_lossy(num);
+        if self.mode == DecodeMode::Strict && !is_canonical_number(&num, true) {
+            return Err(self.error_at(start, DecodeErrorKind::NotCanonical(format!("integer {num}"))));
+        }
+        num.parse().map_err(|_| self.error_at(start, DecodeErrorKind::InvalidFormat(format!("invalid integer {num}"))))
+    }
+
+    fn decode_list(&mut self) -> Result<Value<'a>, DecodeError> {
+        self.enter(b'l')?;
+        let mut list = vec![];
+        while !self.at_end()? {
+            self.path.push(PathSegment::Index(list.len()));
+            list.push(self.decode_value()?);
+            self.path.pop();
+        }
+        self.leave();
+        Ok(Value::List(list))
+    }
+
+    fn decode_key(&mut self) -> Result<&'a [u8], DecodeError> {
+        match self.peek() {
+            Some(b'0'..=b'9') => self.decode_string(),
+            Some(_) => Err(self.error(DecodeErrorKind::InvalidFormat("dict key is not a string".to_string()))),
+            None => Err(self.error(DecodeErrorKind::UnexpectedEnd)),
+        }
+    }
+
+    fn decode_dict(&mut self) -> Result<Value<'a>, DecodeError> {
+        self.enter(b'd')?;
+        let mut dict = BTreeMap::new();
+        let mut previous_key = None;
+        while !self.at_end()? {
+            let key_pos = self.pos;
+            let key = self.decode_key()?;
+            self.path.push(PathSegment::Key(key));
+            if self.mode == DecodeMode::Strict {
+                match previous_key {
+                    Some(previous) if previous == key => {
+                        return Err(self.error_at(key_pos, DecodeErrorKind::NotCanonical("duplicate dict key".to_string())));
+                    },
+                    Some(previous) if previous > key => {
+                        return Err(self.error_at(key_pos, DecodeErrorKind::NotCanonical("dict keys are not sorted".to_string())));
+                    },
+                    _ => previous_key = Some(key),
+                }
+            }
+            let value = self.decode_value()?;
+            self.path.pop();
+            dict.insert(key, value);
+        }
+        self.leave();
+        Ok(Value::Dict(dict))
+    }
+}
+
+/// Only the digits without leading zeros, and the minus sign for the non-zero integers
+fn is_canonical_number(num: &str, allow_negative: bool) -> bool {
+    let digits = match num.strip_prefix('-') {
+        Some(digits) if allow_negative => digits,
+        Some(_) => return false,
+        None => num,
+    };
+    let is_digits = !digits.is_empty() && digits.bytes().all(|byte| byte.is_ascii_digit());
+    let is_zero = digits == "0";
+    let has_leading_zero = digits.len() > 1 && digits.starts_with('0');
+    is_digits && !has_leading_zero && !(is_zero && digits.len() < num.len())
+}
+
+/// Decodes the value from the bencoded input, the strings and bytes can be borrowed from the input
+pub(crate) fn from_bytes<'de, T: Deserialize<'de>>(input: &'de [u8]) -> Result<T, DecodeError> {
+    let mut deserializer = Deserializer::new(input)?;
+    let value = T::deserialize(&mut deserializer).map_err(|error| deserializer.decoder.locate(error, deserializer.decoder.pos))?;
+    if !deserializer.decoder.rest().is_empty() {
+        return Err(deserializer.decoder.error(DecodeErrorKind::InvalidFormat("input is not completely consumed".to_string())));
+    }
+    Ok(value)
+}
+
+/// Reads the values directly from the input, without building the intermediate `Value`
+pub(crate) struct Deserializer<'de> {
+    decoder: Decoder<'de>,
+}
+impl<'de> Deserializer<'de> {
+    pub fn new(input: &'de [u8]) -> Result<Self, DecodeError> {
+        Ok(Self{ decoder: Decoder::new(input, DecodeOptions::default())? })
+    }
+
+    fn peek(&self) -> Result<u8, DecodeError> {
+        self.decoder.peek().ok_or_else(|| self.decoder.error(DecodeErrorKind::UnexpectedEnd))
+    }
+
+    fn parse_int(&mut self) -> Result<i64, DecodeError> {
+        self.decoder.decode_int()
+    }
+
+    fn parse_bytes(&mut self) -> Result<&'de [u8], DecodeError> {
+        self.decoder.decode_string()
+    }
+
+    fn parse_str(&mut self) -> Result<&'de str, DecodeError> {
+        let start = self.decoder.pos;
+        let bytes = self.decoder.decode_string()?;
+        std::str::from_utf8(bytes).map_err(|_| self.decoder.error_at(start, DecodeErrorKind::InvalidValue("string is not a valid utf8".to_string())))
+    }
+
+    /// Skips the start of a list or a dict
+    fn start_container(&mut self, start: u8) -> Result<(), DecodeError> {
+        self.decoder.enter(start)
+    }
+
+    /// The visitor could have stopped before the end of the list or dict
+    fn end_container(&mut self) -> Result<(), DecodeError> {
+        if !self.decoder.at_end()? {
+            return Err(self.decoder.error(DecodeErrorKind::InvalidValue("list or dict has more elements than expected".to_string())));
+        }
+        self.decoder.leave();
+        Ok(())
+    }
+
+    fn at_end(&self) -> Result<bool, DecodeError> {
+        self.decoder.at_end()
+    }
+}
+
+impl<'de> serde::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = DecodeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.peek()? {
+            b'i' => visitor.visit_i64(self.parse_int()?),
+            b'0'..=b'9' => visitor.visit_borrowed_bytes(self.parse_bytes()?),
+            b'l' => self.deserialize_seq(visitor),
+            b'd' => self.deserialize_map(visitor),
+            first => Err(self.decoder.error(DecodeErrorKind::InvalidFormat(format!("can't parse a value that starts with {first}")))),
+        }
+    }
+
+    /// There are no booleans in bencode, they are usually encoded as 0 and 1
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let start = self.decoder.pos;
+        match self.parse_int()? {
+            0 => visitor.visit_bool(false),
+            1 => visitor.visit_bool(true),
+            int => Err(self.decoder.error_at(start, DecodeErrorKind::InvalidValue(format!("expected a boolean, got {int}")))),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_borrowed_str(self.parse_str()?)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_borrowed_bytes(self.parse_bytes()?)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    /// Missing fields are handled by serde, so the present value is always Some
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.start_container(b'l')?;
+        let value = visitor.visit_seq(ListAccess{ de: self, index: 0 })?;
+        self.end_container()?;
+        Ok(value)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.start_container(b'd')?;
+        let value = visitor.visit_map(DictAccess{ de: self })?;
+        self.end_container()?;
+        Ok(value)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_map(visitor)
+    }
+
+    /// Unit variants are strings, the other ones are dicts with a single key
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+        if self.peek()? != b'd' {
+            return visitor.visit_enum(serde::de::value::BorrowedStrDeserializer::new(self.parse_str()?));
+        }
+        self.start_container(b'd')?;
+        let value = visitor.visit_enum(&mut *self)?;
+        self.end_container()?;
+        Ok(value)
+    }
+
+    serde::forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char
+        unit unit_struct tuple tuple_struct identifier ignored_any
+    }
+}
+
+struct ListAccess<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    index: usize,
+}
+impl<'de> SeqAccess<'de> for ListAccess<'_, 'de> {
+    type Error = DecodeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error> {
+        if self.de.at_end()? {
+            return Ok(None);
+        }
+        self.de.decoder.path.push(PathSegment::Index(self.index));
+        let value = seed.deserialize(&mut *self.de)?;
+        self.de.decoder.path.pop();
+        self.index += 1;
+        Ok(Some(value))
+    }
+}
+
+struct DictAccess<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+}
+impl<'de> MapAccess<'de> for DictAccess<'_, 'de> {
+    type Error = DecodeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error> {
+        if self.de.at_end()? {
+            return Ok(None);
+        }
+        // the key is read ahead for the path, the seed reads it again
+        let (key_pos, elements) = (self.de.decoder.pos, self.de.decoder.elements);
+        let key = self.de.decoder.decode_key()?;
+        (self.de.decoder.pos, self.de.decoder.elements) = (key_pos, elements);
+        self.de.decoder.path.push(PathSegment::Key(key));
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Self::Error> {
+        let value = seed.deserialize(&mut *self.de)?;
+        self.de.decoder.path.pop();
+        Ok(value)
+    }
+}
+
+impl<'de> EnumAccess<'de> for &mut Deserializer<'de> {
+    type Error = DecodeError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self::Variant), Self::Error> {
+        let variant = seed.deserialize(&mut *self)?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for &mut Deserializer<'de> {
+    type Error = DecodeError;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        Err(self.decoder.error(DecodeErrorKind::InvalidValue("expected a string for a unit variant".to_string())))
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Self::Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Self::Error> {
+        serde::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+        serde::Deserializer::deserialize_map(self, visitor)
     }
-    Ok((Value::Dict(dict), input))
 }
 
 #[cfg(test)]
 mod test {
-    use crate::custom_bencode::json_encode_value;
+    use anyhow::Context;
+    use serde::de::IgnoredAny;
+    use serde::Deserialize;
+    use crate::custom_bencode::{json_encode_value, to_bytes, BinaryFormat};
+    use crate::custom_bencode::test::{random_value, Random};
+    use crate::torrent::Torrent;
     use super::*;
 
     #[test]
     fn test_parse() -> anyhow::Result<()> {
         let res = decode_value_str("6:hello:")?;
         assert_eq!(Value::Str("hello:".as_bytes()), res);
-        assert_eq!("\"hello:\"", json_encode_value(res)?);
+        assert_eq!("\"hello:\"", json_encode_value(&res, BinaryFormat::Bytes));
 
         let res = decode_value_str("i52e")?;
         assert_eq!(Value::Int(52), res);
-        assert_eq!("52", json_encode_value(res)?);
+        assert_eq!("52", json_encode_value(&res, BinaryFormat::Bytes));
         let res = decode_value_str("i-52e")?;
         assert_eq!(Value::Int(-52), res);
-        assert_eq!("-52", json_encode_value(res)?);
+        assert_eq!("-52", json_encode_value(&res, BinaryFormat::Bytes));
 
         let res = decode_value_str("l5:helloi52ee")?;
         let expected = Value::List([Value::Str("hello".as_bytes()), Value::Int(52)].to_vec());
         assert_eq!(expected, res);
-        assert_eq!("[\"hello\",52]", json_encode_value(res)?);
+        assert_eq!("[\"hello\",52]", json_encode_value(&res, BinaryFormat::Bytes));
         let res = decode_value_str("ll5:helloi52eee")?;
         assert_eq!(Value::List([expected].to_vec()), res);
-        assert_eq!("[[\"hello\",52]]", json_encode_value(res)?);
+        assert_eq!("[[\"hello\",52]]", json_encode_value(&res, BinaryFormat::Bytes));
 
         let res = decode_value_str("d3:foo3:bar5:helloi52ee")?;
         let mut expected = BTreeMap::new();
-        expected.insert("foo", Value::Str("bar".as_bytes()));
-        expected.insert("hello", Value::Int(52));
+        expected.insert("foo".as_bytes(), Value::Str("bar".as_bytes()));
+        expected.insert("hello".as_bytes(), Value::Int(52));
         assert_eq!(Value::Dict(expected), res);
-        assert_eq!("{\"foo\":\"bar\",\"hello\":52}", json_encode_value(res)?);
+        assert_eq!("{\"foo\":\"bar\",\"hello\":52}", json_encode_value(&res, BinaryFormat::Bytes));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_binary_keys() -> anyhow::Result<()> {
+        // scrape responses are keyed by the raw info hashes
+        let mut input = b"d5:filesd20:".to_vec();
+        input.extend_from_slice(&[0xff; 20]);
+        input.extend_from_slice(b"d8:completei5eeee");
+        let value = decode_value(&input)?;
+        let files = value.get("files").and_then(Value::as_dict).context("files should be a dict")?;
+        assert_eq!(Some(5), files.get(&[0xff; 20][..]).and_then(|file| file.get("complete")).and_then(Value::as_int));
+        assert_eq!(input, to_bytes(&value)?, "value should be encoded byte for byte");
+        Ok(())
+    }
+
+    fn strict() -> DecodeOptions {
+        DecodeOptions{ mode: DecodeMode::Strict, ..Default::default() }
+    }
+
+    #[test]
+    fn test_strict_mode() -> anyhow::Result<()> {
+        for input in ["i-0e", "i03e", "i+5e", "05:hello", "d1:bi1e1:ai2ee", "d1:ai1e1:ai2ee", "li1ed1:ai1e1:ai2eee"] {
+            assert!(decode_value_str(input).is_ok(), "lenient mode should accept {input}");
+            assert!(decode_value_with_options(input.as_bytes(), strict()).is_err(), "strict mode should reject {input}");
+        }
+        assert_eq!(Value::Int(2), decode_value_str("d1:ai1e1:ai2ee")?.get("a").cloned().context("key should exist")?, "last duplicate should win");
+        let error = decode_value_with_options(b"d1:ai1e1:bi01ee", strict()).unwrap_err();
+        assert!(format!("{error:#}").contains("at byte 10"), "error should point to the integer: {error:#}");
+        let error = decode_value_with_options(b"d1:bi1e1:ai2ee", strict()).unwrap_err();
+        assert!(format!("{error:#}").contains("at byte 7"), "error should point to the key: {error:#}");
+
+        for input in ["i0e", "i-10e", "0:", "d1:ai0e1:bli-1eee", "d1:a0:2:aai1ee"] {
+            assert!(decode_value_with_options(input.as_bytes(), strict()).is_ok(), "strict mode should accept {input}");
+        }
+        decode_value_with_options(&std::fs::read("sample.torrent")?, strict())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_location() -> anyhow::Result<()> {
+        let input = b"d4:infod5:filesld6:lengthi1eed6:lengthixeeeee";
+        let error = decode_value(input).unwrap_err();
+        assert_eq!(DecodeErrorKind::InvalidFormat("invalid integer x".to_string()), error.kind);
+        assert_eq!(Some(38), error.offset);
+        assert_eq!("info.files[1].length", error.path);
+        assert_eq!(format!("{}|{}", hex::encode(b"6:length"), hex::encode(b"ixeeeee")), error.excerpt);
+
+        let error = decode_value(b"l1:a5:abc").unwrap_err();
+        assert_eq!(DecodeErrorKind::UnexpectedEnd, error.kind);
+        assert_eq!((Some(4), "[1]"), (error.offset, error.path.as_str()));
+
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct File {
+            length: u32,
+        }
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Info {
+            files: Vec<File>,
+        }
+        let error = from_bytes::<Info>(b"d5:filesld6:lengthi1eed6:lengthi-1eeee").unwrap_err();
+        assert!(matches!(error.kind, DecodeErrorKind::InvalidValue(_)), "type mismatch should be an invalid value: {error}");
+        assert_eq!("files[1].length", error.path);
+        let error = from_bytes::<Info>(b"d5:filesld6:lengthi1eedeee").unwrap_err();
+        assert_eq!("files[1]", error.path, "missing field should point to the dict: {error}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_limits() -> anyhow::Result<()> {
+        let limits = DecodeLimits::default();
+        let nested = |depth: usize| format!("{}{}", "l".repeat(depth), "e".repeat(depth));
+        decode_value(nested(limits.max_depth).as_bytes())?;
+        let error = decode_value(nested(limits.max_depth + 1).as_bytes()).unwrap_err();
+        assert_eq!(DecodeErrorKind::DepthLimit(limits.max_depth), error.kind);
+
+        // would overflow the stack without the limit
+        let deep = "l".repeat(1_000_000);
+        assert_eq!(DecodeErrorKind::DepthLimit(limits.max_depth), decode_value(deep.as_bytes()).unwrap_err().kind);
+        assert_eq!(DecodeErrorKind::DepthLimit(limits.max_depth), from_bytes::<IgnoredAny>(deep.as_bytes()).unwrap_err().kind);
+        let torrent = format!("d8:announce3:url4:infod7:unknown{deep}");
+        let error = from_bytes::<Torrent>(torrent.as_bytes()).err().context("deep torrent should fail")?;
+        assert_eq!(DecodeErrorKind::DepthLimit(limits.max_depth), error.kind);
+
+        let options = DecodeOptions{ limits: DecodeLimits{ max_elements: 3, max_size: 12, ..limits }, ..Default::default() };
+        decode_value_with_options(b"li1ei2ee", options)?;
+        let error = decode_value_with_options(b"li1ei2ei3ee", options).unwrap_err();
+        assert_eq!(DecodeErrorKind::ElementLimit(3), error.kind);
+        let error = decode_value_with_options(b"d1:a0:1:b0:e", options).unwrap_err();
+        assert_eq!(DecodeErrorKind::ElementLimit(3), error.kind, "keys should be counted");
+        let error = decode_value_with_options(b"12:hello world", options).unwrap_err();
+        assert_eq!(DecodeErrorKind::SizeLimit(12), error.kind);
+        Ok(())
+    }
+
+    /// Randomized robustness test over mutated valid values, the coverage guided fuzzing is in fuzz/fuzz_targets/decode.rs.
+    /// Only panics and stack overflows fail the test, decoding errors are expected
+    #[test]
+    fn test_random_inputs() -> anyhow::Result<()> {
+        const MUTATIONS: &[u8] = b"ilde:-+0123456789\xff";
+        let small = DecodeOptions{ limits: DecodeLimits{ max_depth: 3, max_elements: 20, max_size: 200 }, ..Default::default() };
+        let mut random = Random(0x9e3779b97f4a7c15);
+        for _ in 0..5000 {
+            let mut input = to_bytes(&random_value(&mut random, 5))?;
+            for _ in 0..=random.below(4) {
+                let pos = random.below(input.len() as u64 + 1);
+                let byte = MUTATIONS[random.below(MUTATIONS.len() as u64)];
+                match random.below(4) {
+                    0 if pos < input.len() => input[pos] = byte,
+                    1 => input.insert(pos, byte),
+                    2 => input.truncate(pos),
+                    _ => input.extend_from_within(pos..),
+                }
+            }
+            let _ = decode_value(&input);
+            let _ = decode_value_with_options(&input, strict());
+            let _ = decode_value_with_options(&input, small);
+            let _ = raw_dict_value(&input, b"info");
+            let _ = from_bytes::<IgnoredAny>(&input);
+            let _ = from_bytes::<Torrent>(&input);
+            let _ = from_bytes::<Borrowed>(&input);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial() -> anyhow::Result<()> {
+        let options = DecodeOptions::default();
+        let Decoded::Complete(value, rest) = decode_value_partial(b"d8:msg_typei1e5:piecei0eepiece data", options)? else {
+            panic!("value should be complete");
+        };
+        assert_eq!(Some(1), value.get("msg_type").and_then(Value::as_int));
+        assert_eq!(b"piece data", rest);
+
+        let input = b"d3:keyl4:spami-12eee";
+        for length in 0..input.len() {
+            let decoded = decode_value_partial(&input[..length], options)?;
+            assert!(matches!(decoded, Decoded::Incomplete), "prefix of length {length} should need more data");
+        }
+        for input in ["i12x", "12x", "d1:ai1ex", "di1e"] {
+            assert!(decode_value_partial(input.as_bytes(), options).is_err(), "{input} should be a hard error");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream_decoder() -> anyhow::Result<()> {
+        let input = b"d1:ai1eel4:spamei-5e";
+        let mut stream = StreamDecoder::new(DecodeOptions::default());
+        let mut values = vec![];
+        for chunk in input.chunks(3) {
+            stream.feed(chunk);
+            while let Some(value) = stream.next_value()? {
+                values.push(to_bytes(&value)?);
+            }
+        }
+        assert_eq!(vec![b"d1:ai1ee".to_vec(), b"l4:spame".to_vec(), b"i-5e".to_vec()], values);
+        assert!(stream.remainder().is_empty());
+
+        stream.feed(b"d1:a");
+        assert!(stream.next_value()?.is_none(), "incomplete value should wait for more data");
+        stream.feed(b"0:e\x00\x01");
+        assert!(stream.next_value()?.is_some());
+        assert_eq!(b"\x00\x01", stream.remainder());
+        assert!(stream.next_value().is_err(), "invalid data after the value should fail");
+
+        let mut stream = StreamDecoder::new(DecodeOptions{ limits: DecodeLimits{ max_size: 8, ..Default::default() }, ..Default::default() });
+        stream.feed(b"i1ei2ei3ei4ei5e");
+        let mut count = 0;
+        while stream.next_value()?.is_some() {
+            count += 1;
+        }
+        assert_eq!(5, count, "values under the limit should be decoded even if the buffer is over it");
+        stream.feed(b"1234567");
+        assert!(stream.next_value()?.is_none());
+        stream.feed(b"89");
+        let error = stream.next_value().err().context("value over the limit should fail")?;
+        assert_eq!(DecodeErrorKind::SizeLimit(8), error.kind);
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_dict_value() -> anyhow::Result<()> {
+        let input = b"d1:ai1e4:infod1:bi03eee";
+        assert_eq!(Some(&b"d1:bi03ee"[..]), raw_dict_value(input, b"info")?);
+        assert_eq!(None, raw_dict_value(input, b"missing")?);
+        Ok(())
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Borrowed<'a> {
+        name: &'a str,
+        #[serde(with = "serde_bytes")]
+        data: &'a [u8],
+        numbers: Vec<u32>,
+        missing: Option<i64>,
+        nested: Option<Nested>,
+        kind: Kind,
+    }
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Nested {
+        flag: bool,
+    }
+    #[derive(Deserialize, Debug, PartialEq)]
+    enum Kind {
+        Plain,
+        Sized(u32),
+    }
+
+    #[test]
+    fn test_deserializer() -> anyhow::Result<()> {
+        let input = b"d4:data3:\x00\xff\x014:kind5:Plain4:name4:test6:nestedd4:flagi1ee7:numbersli1ei2eee";
+        let value: Borrowed = from_bytes(input)?;
+        let expected = Borrowed {
+            name: "test",
+            data: &[0, 0xff, 1],
+            numbers: vec![1, 2],
+            missing: None,
+            nested: Some(Nested{ flag: true }),
+            kind: Kind::Plain,
+        };
+        assert_eq!(expected, value);
+        let name_pos = input.windows(4).position(|window| window == b"test").unwrap();
+        assert_eq!(input[name_pos..].as_ptr(), value.name.as_ptr(), "strings should be borrowed from the input");
+
+        assert_eq!(Kind::Sized(5), from_bytes::<Kind>(b"d5:Sizedi5ee")?);
+        assert!(from_bytes::<Vec<u32>>(b"li1ei-1ee").is_err(), "negative number should not fit");
+        assert!(from_bytes::<(u32,)>(b"li1ei2ee").is_err(), "extra list elements should be rejected");
+        assert!(from_bytes::<Vec<u32>>(b"li1ee1:x").is_err(), "trailing data should be rejected");
+        assert!(from_bytes::<Nested>(b"di1ei1ee").is_err(), "non string keys should be rejected");
+        Ok(())
+    }
+
+    #[test]
+    fn test_sample_torrent() -> anyhow::Result<()> {
+        let contents = std::fs::read("sample.torrent")?;
+        let torrent: Torrent = from_bytes(&contents)?;
+        let expected: Torrent = serde_bencode::from_bytes(&contents)?;
+        assert_eq!(expected.announce, torrent.announce);
+        assert_eq!(expected.info.get_info_hash()?, torrent.info.get_info_hash()?);
+        assert_eq!(expected.info.pieces, torrent.info.pieces);
+        assert_eq!(expected.info.get_length(), torrent.info.get_length());
 
+        let value = decode_value(&contents)?;
+        assert_eq!(Some(torrent.announce.as_str()), value.get("announce").and_then(Value::as_str));
+        let info = value.get("info");
+        assert_eq!(Some(torrent.info.piece_length as i64), info.and_then(|info| info.get("piece length")).and_then(Value::as_int));
+        assert_eq!(Some(torrent.info.pieces.concat()), info.and_then(|info| info.get("pieces")).and_then(Value::as_bytes).map(<[u8]>::to_vec));
         Ok(())
     }
 }
@@ -1,15 +1,60 @@
 use std::collections::BTreeMap;
-use anyhow::Context;
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use anyhow::bail;
+use clap::ValueEnum;
+use serde::ser::{SerializeMap, SerializeSeq, SerializeStruct, SerializeStructVariant, SerializeTuple, SerializeTupleStruct, SerializeTupleVariant};
+use serde::Serialize;
 
 #[derive(PartialEq, Debug, Clone)]
 pub(crate) enum Value<'a> {
     Int(i64),
     Str(&'a [u8]),
     List(Vec<Value<'a>>),
-    Dict(BTreeMap<&'a str, Value<'a>>),
+    /// keys are raw bytes, some extensions use binary keys, like the info hashes in the scrape responses
+    Dict(BTreeMap<&'a [u8], Value<'a>>),
 }
+/// Accessors for the library users, the commands only use a few of them
+#[allow(dead_code)]
 impl<'a> Value<'a> {
-    #[allow(dead_code)]
+    /// Returns the value of the dict key, None if it's missing or this is not a dict
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<&Value<'a>> {
+        self.as_dict()?.get(key.as_ref())
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(int) => Some(*int),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self {
+            Value::Str(str) => Some(str),
+            _ => None,
+        }
+    }
+
+    /// None if this is not a string, or it's not a valid utf8
+    pub fn as_str(&self) -> Option<&'a str> {
+        std::str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_list(&self) -> Option<&[Value<'a>]> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<&'a [u8], Value<'a>>> {
+        match self {
+            Value::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
     pub fn get_variant_name(&self) -> &str {
         match self {
             Value::Int(_) => "int",
@@ -20,71 +65,677 @@ impl<'a> Value<'a> {
     }
 }
 
-#[allow(dead_code)]
-pub(crate) fn bencode_value(value: &Value) -> Vec<u8> {
-    let mut res = vec![];
-    match value {
-        Value::Int(int) => {
-            res.extend_from_slice(format!("i{int}e").as_bytes());
-        },
-        Value::Str(str) => {
-            res.extend_from_slice(format!("{}:", str.len()).as_bytes());
-            res.extend_from_slice(str);
+impl Serialize for Value<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Int(int) => serializer.serialize_i64(*int),
+            Value::Str(str) => serializer.serialize_bytes(str),
+            Value::List(list) => serializer.collect_seq(list),
+            Value::Dict(dict) => serializer.collect_map(dict.iter().map(|(key, value)| (serde_bytes::Bytes::new(key), value))),
         }
-        Value::List(list) => {
-            res.push(b'l');
-            for value in list {
-                res.extend_from_slice(&bencode_value(value));
-            }
-            res.push(b'e');
+    }
+}
+
+/// Writes the canonical bencode of the value: dict keys are sorted, and there are no duplicate keys
+pub(crate) fn to_writer<W: Write, T: Serialize + ?Sized>(writer: W, value: &T) -> anyhow::Result<()> {
+    let mut serializer = Serializer::new(writer);
+    value.serialize(&mut serializer)?;
+    if serializer.written == 0 {
+        bail!("value is empty, None and unit can not be encoded");
+    }
+    Ok(())
+}
+
+pub(crate) fn to_bytes<T: Serialize + ?Sized>(value: &T) -> anyhow::Result<Vec<u8>> {
+    let mut output = vec![];
+    to_writer(&mut output, value)?;
+    Ok(output)
+}
+
+#[derive(Debug)]
+pub(crate) struct EncodeError(String);
+impl Display for EncodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+impl std::error::Error for EncodeError {}
+impl serde::ser::Error for EncodeError {
+    fn custom<T: Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+impl From<std::io::Error> for EncodeError {
+    fn from(error: std::io::Error) -> Self {
+        Self(format!("failed to write bencode: {error}"))
+    }
+}
+
+/// Values are written as soon as they are serialized, except for the dicts, which are buffered to sort the keys.
+/// `BufMut` can be written to through its `writer()`
+pub(crate) struct Serializer<W: Write> {
+    writer: W,
+    /// None is written as nothing, this is used to skip it in the dicts, and reject it in the lists
+    written: usize,
+}
+impl<W: Write> Serializer<W> {
+    pub fn new(writer: W) -> Self {
+        Self{ writer, written: 0 }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), EncodeError> {
+        self.writer.write_all(bytes)?;
+        self.written += bytes.len();
+        Ok(())
+    }
+
+    fn write_int(&mut self, int: impl Display) -> Result<(), EncodeError> {
+        self.write(format!("i{int}e").as_bytes())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), EncodeError> {
+        self.write(format!("{}:", bytes.len()).as_bytes())?;
+        self.write(bytes)
+    }
+
+    fn unsupported<T>(kind: &str) -> Result<T, EncodeError> {
+        Err(EncodeError(format!("{kind} can not be encoded in bencode")))
+    }
+
+    /// Variants with data are encoded as dicts with a single key
+    fn start_variant(&mut self, variant: &str) -> Result<(), EncodeError> {
+        self.write(b"d")?;
+        self.write_bytes(variant.as_bytes())
+    }
+}
+
+impl<'a, W: Write> serde::Serializer for &'a mut Serializer<W> {
+    type Ok = ();
+    type Error = EncodeError;
+    type SerializeSeq = ListSerializer<'a, W>;
+    type SerializeTuple = ListSerializer<'a, W>;
+    type SerializeTupleStruct = ListSerializer<'a, W>;
+    type SerializeTupleVariant = ListSerializer<'a, W>;
+    type SerializeMap = DictSerializer<'a, W>;
+    type SerializeStruct = DictSerializer<'a, W>;
+    type SerializeStructVariant = DictSerializer<'a, W>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), EncodeError> {
+        self.write_int(v as u8)
+    }
+    fn serialize_i8(self, v: i8) -> Result<(), EncodeError> {
+        self.write_int(v)
+    }
+    fn serialize_i16(self, v: i16) -> Result<(), EncodeError> {
+        self.write_int(v)
+    }
+    fn serialize_i32(self, v: i32) -> Result<(), EncodeError> {
+        self.write_int(v)
+    }
+    fn serialize_i64(self, v: i64) -> Result<(), EncodeError> {
+        self.write_int(v)
+    }
+    fn serialize_i128(self, v: i128) -> Result<(), EncodeError> {
+        self.write_int(v)
+    }
+    fn serialize_u8(self, v: u8) -> Result<(), EncodeError> {
+        self.write_int(v)
+    }
+    fn serialize_u16(self, v: u16) -> Result<(), EncodeError> {
+        self.write_int(v)
+    }
+    fn serialize_u32(self, v: u32) -> Result<(), EncodeError> {
+        self.write_int(v)
+    }
+    fn serialize_u64(self, v: u64) -> Result<(), EncodeError> {
+        self.write_int(v)
+    }
+    fn serialize_u128(self, v: u128) -> Result<(), EncodeError> {
+        self.write_int(v)
+    }
+    fn serialize_f32(self, _v: f32) -> Result<(), EncodeError> {
+        Serializer::<W>::unsupported("float")
+    }
+    fn serialize_f64(self, _v: f64) -> Result<(), EncodeError> {
+        Serializer::<W>::unsupported("float")
+    }
+    fn serialize_char(self, v: char) -> Result<(), EncodeError> {
+        self.write_bytes(v.encode_utf8(&mut [0; 4]).as_bytes())
+    }
+    fn serialize_str(self, v: &str) -> Result<(), EncodeError> {
+        self.write_bytes(v.as_bytes())
+    }
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), EncodeError> {
+        self.write_bytes(v)
+    }
+    fn serialize_none(self) -> Result<(), EncodeError> {
+        Ok(())
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), EncodeError> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<(), EncodeError> {
+        Ok(())
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), EncodeError> {
+        Ok(())
+    }
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<(), EncodeError> {
+        self.write_bytes(variant.as_bytes())
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<(), EncodeError> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _index: u32, variant: &'static str, value: &T) -> Result<(), EncodeError> {
+        self.start_variant(variant)?;
+        value.serialize(&mut *self)?;
+        self.write(b"e")
+    }
+    fn serialize_seq(self, _len: Option<usize>) -> Result<ListSerializer<'a, W>, EncodeError> {
+        self.write(b"l")?;
+        Ok(ListSerializer{ ser: self, closing: 1 })
+    }
+    fn serialize_tuple(self, len: usize) -> Result<ListSerializer<'a, W>, EncodeError> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<ListSerializer<'a, W>, EncodeError> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, variant: &'static str, _len: usize) -> Result<ListSerializer<'a, W>, EncodeError> {
+        self.start_variant(variant)?;
+        self.write(b"l")?;
+        Ok(ListSerializer{ ser: self, closing: 2 })
+    }
+    fn serialize_map(self, _len: Option<usize>) -> Result<DictSerializer<'a, W>, EncodeError> {
+        Ok(DictSerializer{ ser: self, entries: vec![], key: None, closing: 0 })
+    }
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<DictSerializer<'a, W>, EncodeError> {
+        self.serialize_map(Some(len))
+    }
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, variant: &'static str, _len: usize) -> Result<DictSerializer<'a, W>, EncodeError> {
+        self.start_variant(variant)?;
+        Ok(DictSerializer{ ser: self, entries: vec![], key: None, closing: 1 })
+    }
+}
+
+pub(crate) struct ListSerializer<'a, W: Write> {
+    ser: &'a mut Serializer<W>,
+    /// lists of the variants are also wrapped into a dict
+    closing: usize,
+}
+impl<W: Write> ListSerializer<'_, W> {
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EncodeError> {
+        let written = self.ser.written;
+        value.serialize(&mut *self.ser)?;
+        if self.ser.written == written {
+            return Serializer::<W>::unsupported("None or unit inside of a list");
         }
-        Value::Dict(dict) => {
-            res.push(b'd');
-            for (key, value) in dict {
-                let key_bytes = key.as_bytes();
-                res.extend_from_slice(format!("{}:", key_bytes.len()).as_bytes());
-                res.extend_from_slice(key_bytes);
-                res.extend_from_slice(&bencode_value(value));
-            }
-            res.push(b'e');
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        self.ser.write(&b"ee"[..self.closing])
+    }
+}
+impl<W: Write> SerializeSeq for ListSerializer<'_, W> {
+    type Ok = ();
+    type Error = EncodeError;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EncodeError> {
+        self.element(value)
+    }
+    fn end(self) -> Result<(), EncodeError> {
+        ListSerializer::end(self)
+    }
+}
+impl<W: Write> SerializeTuple for ListSerializer<'_, W> {
+    type Ok = ();
+    type Error = EncodeError;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EncodeError> {
+        self.element(value)
+    }
+    fn end(self) -> Result<(), EncodeError> {
+        ListSerializer::end(self)
+    }
+}
+impl<W: Write> SerializeTupleStruct for ListSerializer<'_, W> {
+    type Ok = ();
+    type Error = EncodeError;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EncodeError> {
+        self.element(value)
+    }
+    fn end(self) -> Result<(), EncodeError> {
+        ListSerializer::end(self)
+    }
+}
+impl<W: Write> SerializeTupleVariant for ListSerializer<'_, W> {
+    type Ok = ();
+    type Error = EncodeError;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EncodeError> {
+        self.element(value)
+    }
+    fn end(self) -> Result<(), EncodeError> {
+        ListSerializer::end(self)
+    }
+}
+
+/// Entries are collected and sorted by the raw key bytes when the dict ends
+pub(crate) struct DictSerializer<'a, W: Write> {
+    ser: &'a mut Serializer<W>,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    key: Option<Vec<u8>>,
+    /// dicts of the variants are also wrapped into a dict
+    closing: usize,
+}
+impl<W: Write> DictSerializer<'_, W> {
+    /// Keys are serialized like the values, and have to be strings
+    fn key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), EncodeError> {
+        let encoded = to_encoded(key)?;
+        let Some(colon) = encoded.iter().position(|byte| *byte == b':').filter(|_| encoded[0].is_ascii_digit()) else {
+            return Serializer::<W>::unsupported("dict key that is not a string");
+        };
+        self.key = Some(encoded[colon + 1..].to_vec());
+        Ok(())
+    }
+
+    /// None values are skipped, like missing optional fields
+    fn value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EncodeError> {
+        let Some(key) = self.key.take() else {
+            return Err(EncodeError("dict value is serialized before its key".to_string()));
+        };
+        let encoded = to_encoded(value)?;
+        if !encoded.is_empty() {
+            self.entries.push((key, encoded));
+        }
+        Ok(())
+    }
+
+    fn end(mut self) -> Result<(), EncodeError> {
+        self.entries.sort_by(|(left, _), (right, _)| left.cmp(right));
+        if let Some(pair) = self.entries.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(EncodeError(format!("duplicate dict key {}", String::from_utf8_lossy(&pair[0].0))));
+        }
+        self.ser.write(b"d")?;
+        for (key, value) in &self.entries {
+            self.ser.write_bytes(key)?;
+            self.ser.write(value)?;
         }
+        self.ser.write(b"e")?;
+        self.ser.write(&b"e"[..self.closing])
+    }
+}
+impl<W: Write> SerializeMap for DictSerializer<'_, W> {
+    type Ok = ();
+    type Error = EncodeError;
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), EncodeError> {
+        self.key(key)
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EncodeError> {
+        self.value(value)
+    }
+    fn end(self) -> Result<(), EncodeError> {
+        DictSerializer::end(self)
+    }
+}
+impl<W: Write> SerializeStruct for DictSerializer<'_, W> {
+    type Ok = ();
+    type Error = EncodeError;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), EncodeError> {
+        self.key(key)?;
+        self.value(value)
+    }
+    fn end(self) -> Result<(), EncodeError> {
+        DictSerializer::end(self)
     }
+}
+impl<W: Write> SerializeStructVariant for DictSerializer<'_, W> {
+    type Ok = ();
+    type Error = EncodeError;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), EncodeError> {
+        self.key(key)?;
+        self.value(value)
+    }
+    fn end(self) -> Result<(), EncodeError> {
+        DictSerializer::end(self)
+    }
+}
+
+fn to_encoded<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, EncodeError> {
+    let mut serializer = Serializer::new(vec![]);
+    value.serialize(&mut serializer)?;
+    Ok(serializer.writer)
+}
+
+/// How the strings that are not valid utf8 are rendered in json, the valid ones are always json strings
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub(crate) enum BinaryFormat {
+    /// hex string, it can not be told apart from a text string
+    Hex,
+    /// base64 string, it can not be told apart from a text string
+    Base64,
+    /// `{"$bytes": "<hex>"}` object, keys are `"$bytes:<hex>"` strings. This can be encoded back exactly
+    #[default]
+    Bytes,
+}
+
+/// the object key, and the dict key prefix that mark the raw bytes in the json
+pub(crate) const BYTES_MARKER: &str = "$bytes";
+
+pub(crate) fn json_encode_value(value: &Value, format: BinaryFormat) -> String {
+    let mut res = String::new();
+    write_json_value(&mut res, value, format);
     res
 }
 
-pub(crate) fn json_encode_value(value: Value) -> anyhow::Result<String> {
+fn write_json_value(res: &mut String, value: &Value, format: BinaryFormat) {
     match value {
-        Value::Int(int) => Ok(int.to_string()),
-        Value::Str(str) => {
-            let str = std::str::from_utf8(str).context("string is not a valid utf8")?;
-            Ok(format!("\"{str}\""))
-        }
+        Value::Int(int) => res.push_str(&int.to_string()),
+        Value::Str(str) => match (std::str::from_utf8(str), format) {
+            (Ok(str), _) => write_json_string(res, str),
+            (Err(_), BinaryFormat::Hex) => write_json_string(res, &hex::encode(str)),
+            (Err(_), BinaryFormat::Base64) => write_json_string(res, &base64_encode(str)),
+            (Err(_), BinaryFormat::Bytes) => {
+                res.push('{');
+                write_json_string(res, BYTES_MARKER);
+                res.push(':');
+                write_json_string(res, &hex::encode(str));
+                res.push('}');
+            },
+        },
         Value::List(list) => {
-            let mut res = String::from("[");
-            for value in list {
-                res.push_str(&json_encode_value(value)?);
-                res.push(',');
-            }
-            if res.len() > 1 {
-                // remove the trailing comma
-                res.pop();
+            res.push('[');
+            for (index, value) in list.iter().enumerate() {
+                if index > 0 {
+                    res.push(',');
+                }
+                write_json_value(res, value, format);
             }
             res.push(']');
-            Ok(res)
-        }
+        },
         Value::Dict(dict) => {
-            let mut res = String::from("{");
-            for (key, value) in dict {
-                res.push_str(&format!("\"{key}\":"));
-                res.push_str(&json_encode_value(value)?);
-                res.push(',');
-            }
-            if res.len() > 1 {
-                // remove the trailing comma
-                res.pop();
+            res.push('{');
+            for (index, (key, value)) in dict.iter().enumerate() {
+                if index > 0 {
+                    res.push(',');
+                }
+                write_json_string(res, &json_key(key, format));
+                res.push(':');
+                write_json_value(res, value, format);
             }
             res.push('}');
-            Ok(res)
+        },
+    }
+}
+
+/// Keys that look like the marked bytes are marked too, so that they are not mistaken for them
+fn json_key(key: &[u8], format: BinaryFormat) -> String {
+    match (std::str::from_utf8(key), format) {
+        (Ok(key), BinaryFormat::Bytes) if key.starts_with(BYTES_MARKER) => format!("{BYTES_MARKER}:{}", hex::encode(key)),
+        (Ok(key), _) => key.to_string(),
+        (Err(_), BinaryFormat::Hex) => hex::encode(key),
+        (Err(_), BinaryFormat::Base64) => base64_encode(key),
+        (Err(_), BinaryFormat::Bytes) => format!("{BYTES_MARKER}:{}", hex::encode(key)),
+    }
+}
+
+fn write_json_string(res: &mut String, str: &str) {
+    res.push('"');
+    for char in str.chars() {
+        match char {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            '\u{08}' => res.push_str("\\b"),
+            '\u{0c}' => res.push_str("\\f"),
+            char if (char as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", char as u32)),
+            char => res.push(char),
+        }
+    }
+    res.push('"');
+}
+
+/// Encodes the json, that uses the same conventions as `json_encode_value` with the bytes format
+pub(crate) fn json_to_bencode<W: Write>(writer: W, json: &serde_json::Value) -> anyhow::Result<()> {
+    to_writer(writer, &JsonValue{ json, path: String::new() })
+}
+
+/// The path is only used in the errors
+struct JsonValue<'a> {
+    json: &'a serde_json::Value,
+    path: String,
+}
+impl JsonValue<'_> {
+    fn child(&self, key: impl Display, is_index: bool) -> String {
+        match (self.path.is_empty(), is_index) {
+            (_, true) => format!("{}[{key}]", self.path),
+            (true, false) => key.to_string(),
+            (false, false) => format!("{}.{key}", self.path),
+        }
+    }
+
+    fn location(&self) -> String {
+        if self.path.is_empty() { "the root".to_string() } else { self.path.clone() }
+    }
+}
+impl Serialize for JsonValue<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::Error;
+        match self.json {
+            serde_json::Value::Null => Err(S::Error::custom(format!("null can not be encoded, at {}", self.location()))),
+            // bencode has no booleans, they are not turned into integers implicitly
+            serde_json::Value::Bool(bool) => Err(S::Error::custom(format!("booleans can not be encoded, got {bool} at {}", self.location()))),
+            serde_json::Value::Number(number) => {
+                if let Some(int) = number.as_i64() {
+                    serializer.serialize_i64(int)
+                } else if number.is_u64() {
+                    // the decoder only accepts integers that fit into i64, so they would not be read back
+                    Err(S::Error::custom(format!("integers larger than {} can not be encoded, got {number} at {}", i64::MAX, self.location())))
+                } else {
+                    Err(S::Error::custom(format!("floats can not be encoded, got {number} at {}", self.location())))
+                }
+            },
+            serde_json::Value::String(str) => serializer.serialize_str(str),
+            serde_json::Value::Array(list) => {
+                let mut seq = serializer.serialize_seq(Some(list.len()))?;
+                for (index, json) in list.iter().enumerate() {
+                    seq.serialize_element(&JsonValue{ json, path: self.child(index, true) })?;
+                }
+                seq.end()
+            },
+            serde_json::Value::Object(object) => {
+                if let Some(bytes) = object.get(BYTES_MARKER).filter(|_| object.len() == 1) {
+                    let bytes = bytes
+                        .as_str()
+                        .and_then(|bytes| hex::decode(bytes).ok())
+                        .ok_or_else(|| S::Error::custom(format!("{BYTES_MARKER} should be a hex string, at {}", self.location())))?;
+                    return serializer.serialize_bytes(&bytes);
+                }
+                let mut map = serializer.serialize_map(Some(object.len()))?;
+                for (key, json) in object {
+                    let key_bytes = match key.strip_prefix(BYTES_MARKER).and_then(|key| key.strip_prefix(':')) {
+                        Some(hex_key) => hex::decode(hex_key)
+                            .map_err(|_| S::Error::custom(format!("key {key} should be hex after {BYTES_MARKER}:, at {}", self.location())))?,
+                        None => key.as_bytes().to_vec(),
+                    };
+                    map.serialize_entry(serde_bytes::Bytes::new(&key_bytes), &JsonValue{ json, path: self.child(key, false) })?;
+                }
+                map.end()
+            },
+        }
+    }
+}
+
+/// Standard alphabet with padding
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut res = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], chunk.get(1).copied().unwrap_or(0), chunk.get(2).copied().unwrap_or(0)];
+        let triple = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for char_no in 0..4 {
+            if char_no <= chunk.len() {
+                res.push(ALPHABET[(triple >> (18 - char_no * 6)) as usize & 0x3f] as char);
+            } else {
+                res.push('=');
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use bytes::{BufMut, BytesMut};
+    use anyhow::Context;
+    use crate::custom_bdecode::{decode_value, from_bytes};
+    use super::*;
+
+    #[derive(Serialize, serde::Deserialize, Debug, PartialEq)]
+    struct Message {
+        zeta: u32,
+        #[serde(with = "serde_bytes")]
+        alpha: Vec<u8>,
+        missing: Option<String>,
+        list: Vec<Kind>,
+        #[serde(rename = "b key")]
+        b_key: bool,
+    }
+    #[derive(Serialize, serde::Deserialize, Debug, PartialEq)]
+    enum Kind {
+        Plain,
+        Sized(u32),
+        Pair(u8, u8),
+        Named{ x: i64 },
+    }
+
+    struct DuplicateKeys;
+    impl Serialize for DuplicateKeys {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_map([("a", 1), ("a", 2)])
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_serializer() -> anyhow::Result<()> {
+        let message = Message {
+            zeta: 7,
+            alpha: vec![0, 0xff],
+            missing: None,
+            list: vec![Kind::Plain, Kind::Sized(5), Kind::Pair(1, 2), Kind::Named{ x: -3 }],
+            b_key: true,
+        };
+        let encoded = to_bytes(&message)?;
+        let expected = b"d5:alpha2:\x00\xff5:b keyi1e4:listl5:Plaind5:Sizedi5eed4:Pairli1ei2eeed5:Namedd1:xi-3eeee4:zetai7ee";
+        assert_eq!(String::from_utf8_lossy(expected), String::from_utf8_lossy(&encoded), "keys should be sorted, None should be skipped");
+        assert_eq!(message, from_bytes::<Message>(&encoded)?);
+
+        let mut buffer = BytesMut::new().writer();
+        to_writer(&mut buffer, &message)?;
+        assert_eq!(encoded, buffer.into_inner().to_vec(), "BufMut should be supported through its writer");
+
+        assert!(to_bytes(&1.5).is_err(), "floats should be rejected");
+        assert!(to_bytes(&vec![None, Some(1)]).is_err(), "None inside of a list should be rejected");
+        assert!(to_bytes(&None::<u32>).is_err(), "empty output should be rejected");
+        assert!(to_bytes(&BTreeMap::from([(1, 2)])).is_err(), "non string keys should be rejected");
+        assert!(to_bytes(&DuplicateKeys).is_err(), "duplicate keys should be rejected");
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_encode() -> anyhow::Result<()> {
+        let value = decode_value(b"d1:\"5:a\\\n\x01\t1:ll2:\xff\x00ee")?;
+        assert_eq!(r#"{"\"":"a\\\n\u0001\t","l":[{"$bytes":"ff00"}]}"#, json_encode_value(&value, BinaryFormat::Bytes));
+        assert_eq!(r#"{"\"":"a\\\n\u0001\t","l":["ff00"]}"#, json_encode_value(&value, BinaryFormat::Hex));
+        assert_eq!(r#"{"\"":"a\\\n\u0001\t","l":["/wA="]}"#, json_encode_value(&value, BinaryFormat::Base64));
+
+        let value = decode_value(b"d6:$bytes1:x2:\xff\x001:ye")?;
+        assert_eq!(r#"{"$bytes:246279746573":"x","$bytes:ff00":"y"}"#, json_encode_value(&value, BinaryFormat::Bytes));
+        assert_eq!(r#"{"$bytes":"x","ff00":"y"}"#, json_encode_value(&value, BinaryFormat::Hex));
+
+        let json = json_encode_value(&decode_value(&std::fs::read("sample.torrent")?)?, BinaryFormat::Bytes);
+        serde_json::from_str::<serde_json::Value>(&json).context("rendered torrent should be a valid json")?;
+
+        assert_eq!(["", "Zg==", "Zm8=", "Zm9v", "Zm9vYg=="], ["", "f", "fo", "foo", "foob"].map(|data| base64_encode(data.as_bytes())));
+        Ok(())
+    }
+
+    /// Small deterministic generator, so that the property tests do not need a separate crate
+    pub(crate) struct Random(pub u64);
+    impl Random {
+        pub fn next(&mut self) -> u64 {
+            // xorshift64
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        pub fn below(&mut self, max: u64) -> usize {
+            (self.next() % max) as usize
+        }
+    }
+
+    const KEYS: &[&[u8]] = &[b"", b"a", b"ab", b"b", b"info", b"piece length", b"~", "é".as_bytes(), b"\x00", b"\xff\xfe"];
+    const BYTES: &[u8] = b"\x00\xff\xfe:eil0123456789d\n\"\\";
+
+    pub(crate) fn random_value(random: &mut Random, depth: u32) -> Value<'static> {
+        let kind = if depth == 0 { random.below(2) } else { random.below(4) };
+        match kind {
+            0 => Value::Int(match random.below(4) {
+                0 => 0,
+                1 => i64::MIN,
+                2 => i64::MAX,
+                _ => random.next() as i64,
+            }),
+            1 => {
+                let start = random.below(BYTES.len() as u64);
+                let end = start + random.below((BYTES.len() - start) as u64 + 1);
+                Value::Str(&BYTES[start..end])
+            },
+            2 => Value::List((0..random.below(5)).map(|_| random_value(random, depth - 1)).collect()),
+            _ => Value::Dict(
+                (0..random.below(5))
+                    .map(|_| (KEYS[random.below(KEYS.len() as u64)], random_value(random, depth - 1)))
+                    .collect()
+            ),
+        }
+    }
+
+    #[test]
+    fn test_json_to_bencode() -> anyhow::Result<()> {
+        let encode = |json: &str| -> anyhow::Result<Vec<u8>> {
+            let mut output = vec![];
+            json_to_bencode(&mut output, &serde_json::from_str(json)?)?;
+            Ok(output)
+        };
+        assert_eq!(b"d1:ai1e1:bl2:\xff\x00i0ee2:\xffxi-5ee".to_vec(), encode(r#"{"b":[{"$bytes":"ff00"},0],"a":1,"$bytes:ff78":-5}"#)?);
+        let error = encode(r#"{"a":[1,{"b":1.5}]}"#).unwrap_err();
+        assert!(format!("{error:#}").contains("a[1].b"), "error should point to the float: {error:#}");
+        let error = encode(r#"{"a":[9223372036854775808]}"#).unwrap_err();
+        assert!(format!("{error:#}").contains("a[0]"), "error should point to the integer that does not fit into i64: {error:#}");
+        assert_eq!(b"i9223372036854775807e".to_vec(), encode("9223372036854775807")?);
+        let error = encode(r#"{"a":true}"#).unwrap_err();
+        assert!(format!("{error:#}").contains("booleans can not be encoded, got true at a"), "booleans should be rejected: {error:#}");
+        assert!(encode(r#"[null]"#).is_err(), "null should be rejected");
+        assert!(encode(r#"{"$bytes":"zz"}"#).is_err(), "invalid hex should be rejected");
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip() -> anyhow::Result<()> {
+        let mut random = Random(0x2545f4914f6cdd1d);
+        for _ in 0..1000 {
+            let value = random_value(&mut random, 4);
+            let encoded = to_bytes(&value)?;
+            let decoded = decode_value(&encoded)?;
+            assert_eq!(value, decoded, "value should survive the round trip");
+            assert_eq!(encoded, to_bytes(&decoded)?, "encoding should be canonical");
+            let json = json_encode_value(&decoded, BinaryFormat::Bytes);
+            let mut from_json = vec![];
+            json_to_bencode(&mut from_json, &serde_json::from_str(&json)?)?;
+            assert_eq!(encoded, from_json, "json should be encoded back exactly");
+        }
+        Ok(())
+    }
+}
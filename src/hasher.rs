@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, OnceLock};
+use anyhow::{bail, Context};
+use sha1::{Digest, Sha1};
+use tokio::sync::{mpsc, oneshot};
+use crate::torrent::HASH_RAW_LENGTH;
+
+/// jobs that can wait in the queue for each hashing thread, the senders wait when the queue is full
+const QUEUE_LENGTH_PER_THREAD: usize = 4;
+
+struct HashJob {
+    state: Sha1,
+    data: Vec<u8>,
+    result: oneshot::Sender<Sha1>,
+}
+
+/// Dedicated threads for hashing, so that the runtime threads are not blocked by it
+pub(crate) struct HashPool {
+    sender: mpsc::Sender<HashJob>,
+}
+impl HashPool {
+    pub fn new(threads_count: usize) -> anyhow::Result<Self> {
+        let (sender, receiver) = mpsc::channel::<HashJob>(threads_count * QUEUE_LENGTH_PER_THREAD);
+        // the threads take the jobs one by one, the lock is only held while waiting for a job
+        let receiver = Arc::new(std::sync::Mutex::new(receiver));
+        for thread_no in 0..threads_count {
+            let receiver = receiver.clone();
+            std::thread::Builder::new()
+                .name(format!("hasher-{thread_no}"))
+                .spawn(move || loop {
+                    let job = receiver.lock().expect("poisoned lock").blocking_recv();
+                    let Some(HashJob{ mut state, data, result }) = job else {
+                        break;
+                    };
+                    state.update(&data);
+                    // the piece could have been abandoned, nobody waits for the result then
+                    let _ = result.send(state);
+                })
+                .context("failed to start hashing thread")?;
+        }
+        Ok(Self{ sender })
+    }
+
+    /// The threads are started on the first use
+    pub fn global() -> &'static Self {
+        static POOL: OnceLock<HashPool> = OnceLock::new();
+        POOL.get_or_init(|| {
+            let threads_count = std::thread::available_parallelism().map(|count| count.get()).unwrap_or(1);
+            Self::new(threads_count).expect("failed to start hashing pool")
+        })
+    }
+
+    /// Waits for a free place in the queue, returns the updated state when the data is hashed
+    async fn update(&self, state: Sha1, data: Vec<u8>) -> anyhow::Result<oneshot::Receiver<Sha1>> {
+        let (result, receiver) = oneshot::channel();
+        if self.sender.send(HashJob{ state, data, result }).await.is_err() {
+            bail!("hashing pool is stopped");
+        }
+        Ok(receiver)
+    }
+
+    pub async fn digest(&self, data: Vec<u8>) -> anyhow::Result<[u8; HASH_RAW_LENGTH]> {
+        let state = self.update(Sha1::new(), data).await?.await.context("hashing pool is stopped")?;
+        Ok(state.finalize().into())
+    }
+}
+
+enum HasherState {
+    Idle(Sha1),
+    Hashing(oneshot::Receiver<Sha1>),
+}
+
+/// Hashes the piece while its blocks are being downloaded. Blocks are hashed as soon as all previous blocks
+/// have arrived, the ones that arrive out of order are kept until the gap is filled
+pub(crate) struct PieceHasher {
+    pool: &'static HashPool,
+    /// None if the hashing has failed
+    state: Option<HasherState>,
+    hashed_length: u64,
+    pending: BTreeMap<u64, Vec<u8>>,
+}
+impl PieceHasher {
+    pub fn new(pool: &'static HashPool) -> Self {
+        Self {
+            pool,
+            state: Some(HasherState::Idle(Sha1::new())),
+            hashed_length: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    pub async fn update(&mut self, begin: u64, block: Vec<u8>) -> anyhow::Result<()> {
+        if begin < self.hashed_length {
+            bail!("block at {begin} overlaps with the data that is already hashed");
+        }
+        self.pending.insert(begin, block);
+        while let Some(block) = self.pending.remove(&self.hashed_length) {
+            self.hashed_length += block.len() as u64;
+            let state = self.take_state().await?;
+            let receiver = self.pool.update(state, block).await?;
+            self.state = Some(HasherState::Hashing(receiver));
+        }
+        Ok(())
+    }
+
+    pub async fn finish(mut self, length: u64) -> anyhow::Result<[u8; HASH_RAW_LENGTH]> {
+        if self.hashed_length != length || !self.pending.is_empty() {
+            bail!("piece data is incomplete, only {} of {length} bytes were hashed", self.hashed_length);
+        }
+        let state = self.take_state().await?;
+        Ok(state.finalize().into())
+    }
+
+    /// Waits until the previous block is hashed
+    async fn take_state(&mut self) -> anyhow::Result<Sha1> {
+        match self.state.take() {
+            Some(HasherState::Idle(state)) => Ok(state),
+            Some(HasherState::Hashing(receiver)) => receiver.await.context("hashing pool is stopped"),
+            None => bail!("previous hashing has failed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::seed::test::get_data;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_piece_hasher() -> anyhow::Result<()> {
+        let pool = Box::leak(Box::new(HashPool::new(2)?));
+        let data = get_data(1000);
+        let expected: [u8; HASH_RAW_LENGTH] = Sha1::digest(&data).into();
+        assert_eq!(expected, pool.digest(data.clone()).await?);
+
+        let mut hasher = PieceHasher::new(pool);
+        for (block_no, block) in data.chunks(100).enumerate() {
+            hasher.update(block_no as u64 * 100, block.to_vec()).await?;
+        }
+        assert_eq!(expected, hasher.finish(1000).await?);
+
+        let mut hasher = PieceHasher::new(pool);
+        hasher.update(500, data[500..].to_vec()).await?;
+        hasher.update(0, data[..200].to_vec()).await?;
+        hasher.update(200, data[200..500].to_vec()).await?;
+        assert_eq!(expected, hasher.finish(1000).await?, "blocks out of order should be hashed in order");
+
+        let mut hasher = PieceHasher::new(pool);
+        hasher.update(500, data[500..].to_vec()).await?;
+        assert!(hasher.finish(1000).await.is_err(), "missing blocks should fail the hashing");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hash_pool_backpressure() -> anyhow::Result<()> {
+        let pool: &'static HashPool = Box::leak(Box::new(HashPool::new(1)?));
+        let data = get_data(100);
+        let expected: [u8; HASH_RAW_LENGTH] = Sha1::digest(&data).into();
+        // more jobs than the queue can hold, the senders should wait instead of failing
+        let mut join_set = tokio::task::JoinSet::new();
+        for _ in 0..QUEUE_LENGTH_PER_THREAD * 4 {
+            let data = data.clone();
+            join_set.spawn(async move { pool.digest(data).await });
+        }
+        while let Some(result) = join_set.join_next().await {
+            assert_eq!(expected, result??);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::{bail, Context};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use crate::peer::{accept_peer, receive_handshake, Peer};
+use crate::torrent::HASH_RAW_LENGTH;
+
+pub(crate) const DEFAULT_PORT: u16 = 6881;
+pub(crate) const MAX_CONNECTIONS: usize = 200;
+pub(crate) const MAX_CONNECTIONS_PER_TORRENT: usize = 50;
+/// accepted peers that were not picked up by the torrent yet
+const INCOMING_QUEUE_SIZE: usize = 16;
+/// wait before accepting again when we are out of file descriptors, so that the connections get a chance to close
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// EMFILE, the process has too many open files
+const TOO_MANY_OPEN_FILES: i32 = 24;
+
+/// Keeps a slot in the global and the per-torrent connection limits while it is alive
+pub(crate) struct ConnectionPermit {
+    _global: OwnedSemaphorePermit,
+    _torrent: OwnedSemaphorePermit,
+}
+
+/// A peer that has connected to us. The connection permit should be kept for as long as the connection is used
+pub(crate) struct IncomingPeer {
+    pub peer: Peer,
+    pub permit: ConnectionPermit,
+}
+
+struct ListenerTorrent {
+    pieces_count: u32,
+    connections: Arc<Semaphore>,
+    sender: mpsc::Sender<IncomingPeer>,
+}
+
+pub(crate) struct PeerListener {
+    listener: TcpListener,
+    port: u16,
+    connections: Arc<Semaphore>,
+    torrents: Mutex<HashMap<[u8; HASH_RAW_LENGTH], ListenerTorrent>>,
+}
+impl PeerListener {
+    /// Port 0 binds to any free port, use `port` to get the actual one
+    pub async fn bind(port: u16, max_connections: usize) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(("0.0.0.0", port)).await.context(format!("failed to listen on port {port}"))?;
+        let port = listener.local_addr().context("failed to get listener address")?.port();
+        let listener = Self {
+            listener,
+            port,
+            connections: Arc::new(Semaphore::new(max_connections)),
+            torrents: Mutex::new(HashMap::new()),
+        };
+        Ok(listener)
+    }
+
+    /// The port that should be announced to the trackers
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Incoming connections for this torrent will be sent into the returned receiver
+    pub fn add_torrent(&self, info_hash: [u8; HASH_RAW_LENGTH], pieces_count: u32, max_connections: usize) -> mpsc::Receiver<IncomingPeer> {
+        let (sender, receiver) = mpsc::channel(INCOMING_QUEUE_SIZE);
+        let torrent = ListenerTorrent {
+            pieces_count,
+            connections: Arc::new(Semaphore::new(max_connections)),
+            sender,
+        };
+        self.torrents.lock().expect("poisoned lock").insert(info_hash, torrent);
+        receiver
+    }
+
+    /// Outgoing connections should also take a permit, so that they are counted towards the limits
+    pub fn try_acquire_permit(&self, info_hash: &[u8; HASH_RAW_LENGTH]) -> anyhow::Result<ConnectionPermit> {
+        let torrents = self.torrents.lock().expect("poisoned lock");
+        let Some(torrent) = torrents.get(info_hash) else {
+            bail!("unknown info hash {}", hex::encode(info_hash));
+        };
+        self.acquire_permit(torrent)
+    }
+
+    fn acquire_permit(&self, torrent: &ListenerTorrent) -> anyhow::Result<ConnectionPermit> {
+        let Ok(global) = self.connections.clone().try_acquire_owned() else {
+            bail!("too many connections");
+        };
+        let Ok(torrent) = torrent.connections.clone().try_acquire_owned() else {
+            bail!("too many connections for the torrent");
+        };
+        Ok(ConnectionPermit{ _global: global, _torrent: torrent })
+    }
+
+    /// Accepts connections until the task is aborted. Failed accepts are logged, they should not stop the listener
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            let (tcp, socket) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    eprintln!("failed to accept a connection: {error}");
+                    if error.raw_os_error() == Some(TOO_MANY_OPEN_FILES) {
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    }
+                    continue;
+                },
+            };
+            let listener = self.clone();
+            tokio::spawn(async move {
+                if let Err(error) = listener.handle_connection(tcp).await {
+                    eprintln!("rejected connection from {socket}: {error:#}");
+                }
+            });
+        }
+    }
+
+    async fn handle_connection(&self, mut tcp: TcpStream) -> anyhow::Result<()> {
+        let handshake_message = receive_handshake(&mut tcp).await?;
+        let (pieces_count, permit, sender) = {
+            let torrents = self.torrents.lock().expect("poisoned lock");
+            let Some(torrent) = torrents.get(&handshake_message.info_hash) else {
+                bail!("unknown info hash {}", hex::encode(handshake_message.info_hash));
+            };
+            let permit = self.acquire_permit(torrent)?;
+            (torrent.pieces_count, permit, torrent.sender.clone())
+        };
+        let peer = accept_peer(tcp, handshake_message, pieces_count).await?;
+        sender.send(IncomingPeer{ peer, permit }).await.context("torrent is no longer accepting peers")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use crate::peer::connect_peer;
+    use super::*;
+
+    async fn start_listener(max_connections: usize) -> anyhow::Result<(Arc<PeerListener>, SocketAddrV4)> {
+        let listener = Arc::new(PeerListener::bind(0, max_connections).await?);
+        let socket = SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.port());
+        Ok((listener, socket))
+    }
+
+    #[tokio::test]
+    async fn test_route_by_info_hash() -> anyhow::Result<()> {
+        let (listener, socket) = start_listener(MAX_CONNECTIONS).await?;
+        let mut first = listener.add_torrent([1; HASH_RAW_LENGTH], 1, MAX_CONNECTIONS_PER_TORRENT);
+        let mut second = listener.add_torrent([2; HASH_RAW_LENGTH], 1, MAX_CONNECTIONS_PER_TORRENT);
+        tokio::spawn(listener.clone().run());
+
+        let _peer = connect_peer([2; HASH_RAW_LENGTH], 1, &socket).await?;
+        assert!(second.recv().await.is_some(), "connection should be routed to the second torrent");
+        assert!(first.try_recv().is_err(), "first torrent should not get any connections");
+
+        let peer = connect_peer([3; HASH_RAW_LENGTH], 1, &socket).await;
+        assert!(peer.is_err(), "unknown info hash should be rejected");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_limits() -> anyhow::Result<()> {
+        let (listener, socket) = start_listener(2).await?;
+        let mut first = listener.add_torrent([1; HASH_RAW_LENGTH], 1, 1);
+        let mut second = listener.add_torrent([2; HASH_RAW_LENGTH], 1, 2);
+        tokio::spawn(listener.clone().run());
+
+        let _peer = connect_peer([1; HASH_RAW_LENGTH], 1, &socket).await?;
+        let first_incoming = first.recv().await.expect("connection should be accepted");
+        let peer = connect_peer([1; HASH_RAW_LENGTH], 1, &socket).await;
+        assert!(peer.is_err(), "per torrent limit should be enforced");
+
+        let _peer = connect_peer([2; HASH_RAW_LENGTH], 1, &socket).await?;
+        let _second_incoming = second.recv().await.expect("connection should be accepted");
+        let peer = connect_peer([2; HASH_RAW_LENGTH], 1, &socket).await;
+        assert!(peer.is_err(), "global limit should be enforced");
+        assert!(listener.try_acquire_permit(&[2; HASH_RAW_LENGTH]).is_err(), "global limit should apply to outgoing connections");
+
+        drop(first_incoming);
+        let _peer = connect_peer([2; HASH_RAW_LENGTH], 1, &socket).await?;
+        assert!(second.recv().await.is_some(), "closed connections should free the limit");
+        Ok(())
+    }
+}
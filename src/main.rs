@@ -1,27 +1,56 @@
 use std::cmp;
-use std::io::SeekFrom;
 use std::net::SocketAddrV4;
-use std::ops::Deref;
 use std::str::FromStr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use anyhow::{bail, Context};
-use clap::{Parser, Subcommand};
+use anyhow::{anyhow, bail, Context};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use tokio::fs::File;
-use tokio::io::{AsyncWriteExt, AsyncSeekExt};
-use tokio::sync::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, watch};
 use tokio::task::JoinSet;
-use crate::custom_bdecode::{decode_value_str};
-use crate::custom_bencode::{json_encode_value};
-use crate::peer::init_peer;
-use crate::torrent::{parse_torrent_from_file, Torrent};
+use crate::custom_bdecode::{decode_value, decode_value_with_options, DecodeMode, DecodeOptions, StreamDecoder};
+use crate::custom_bencode::{json_encode_value, json_to_bencode, BinaryFormat, Value};
+use crate::choker::{ChokerMode, SharedChoker};
+use crate::listener::{IncomingPeer, PeerListener, DEFAULT_PORT, MAX_CONNECTIONS, MAX_CONNECTIONS_PER_TORRENT};
+use crate::preallocate::PreallocationMode;
+use crate::query::query_value;
+use crate::peer::{bitfield_length, connect_peer, init_peer, piece_exists, Peer, PieceRejected};
+use crate::resume::{load_verified_pieces, resume_file_path, ResumeWriter, RESUME_SAVE_INTERVAL};
+use crate::storage::{FileStorage, MemoryStorage, PieceDirStorage, Storage};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use crate::mmap::MmapStorage;
+use crate::partfile::{partfile_path, PartFile};
+use crate::seed::SeedData;
+use crate::staging::{move_files, staging_paths};
+use crate::torrent::{file_segments, parse_torrent_from_file, FileInfo, PieceInfo, Torrent, TorrentInfo, HASH_RAW_LENGTH};
 use crate::tracker::request_peers;
+use crate::verify::verify_data;
 
 mod custom_bdecode;
 mod custom_bencode;
 mod torrent;
 mod tracker;
 mod peer;
+mod seed;
+mod listener;
+mod choker;
+mod resume;
+mod verify;
+mod storage;
+mod cache;
+mod hasher;
+mod preallocate;
+mod staging;
+mod partfile;
+mod sanitize;
+mod query;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod mmap;
+
+/// the peer is not used anymore after rejecting this many pieces in a row
+const MAX_REJECTED_PIECES: usize = 3;
+const STDIN_CHUNK_SIZE: usize = 64 * 1024;
 
 #[derive(Parser)]
 struct Cli {
@@ -31,8 +60,27 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Command {
+    /// Decode bencode to json. Without a value or a file, the values are read from stdin one after another
     Decode {
-        value: String,
+        /// bencoded value
+        value: Option<String>,
+        /// read the bencoded value from the file instead
+        #[arg(long, conflicts_with = "value")]
+        file: Option<String>,
+        /// how the strings that are not valid utf8 are rendered
+        #[arg(long, value_enum, default_value_t)]
+        binary: BinaryFormat,
+        /// reject the input that is not canonically encoded
+        #[arg(long)]
+        strict: bool,
+        /// output only a part of the value, like info.files[2].path, info.pieces|len or info["name.utf-8"]
+        #[arg(long)]
+        query: Option<String>,
+    },
+    /// Encode json to bencode, strings that are not valid utf8 are written like in the decode output
+    Encode {
+        /// json value, read from stdin if it's not set
+        value: Option<String>,
     },
     Info {
         /// torrent file
@@ -58,35 +106,162 @@ enum Command {
         piece: u32,
     },
     Download {
-        /// save location
+        /// save location, the sanitized torrent name in the current directory by default
         #[arg(short = 'o')]
-        save_location: String,
+        save_location: Option<String>,
+        /// torrent file
+        torrent_path: String,
+        #[command(flatten)]
+        options: DownloadOptions,
+    },
+    Seed {
+        /// torrent file
+        torrent_path: String,
+        /// location of the completed data
+        data_path: String,
+        /// port to accept connections on, 0 to pick any free port
+        #[arg(short = 'p', long, default_value_t = DEFAULT_PORT)]
+        port: u16,
+        /// move the data to this location while seeding
+        #[arg(long)]
+        move_to: Option<String>,
+        /// print the disk cache statistics when finished
+        #[arg(long)]
+        stats: bool,
+    },
+    /// Hash-check local data against the torrent
+    Verify {
         /// torrent file
         torrent_path: String,
+        /// data file, or the data directory for multi-file torrents
+        data_path: String,
+        /// output the report as json
+        #[arg(long)]
+        json: bool,
     },
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+enum StorageKind {
+    /// data files at the save location
+    #[default]
+    File,
+    /// data is kept in memory, and written to stdout when the download is finished
+    Memory,
+    /// every piece is written into a separate file in the save location directory
+    PieceDir,
+}
+
+#[derive(Args)]
+struct DownloadOptions {
+    /// where the downloaded data is kept
+    #[arg(long, value_enum, default_value_t)]
+    storage: StorageKind,
+    /// memory map the data files instead of writing through file handles
+    #[arg(long)]
+    mmap: bool,
+    /// how the space for the data files is reserved
+    #[arg(long, value_enum, default_value_t)]
+    preallocate: PreallocationMode,
+    /// directory for the data that is not completed yet, the files get a .part suffix if it's not set
+    #[arg(long)]
+    incomplete_dir: Option<String>,
+    /// index of a file that should not be downloaded, can be repeated
+    #[arg(long, value_name = "FILE_INDEX")]
+    skip: Vec<usize>,
+    /// port to accept connections on, 0 to pick any free port
+    #[arg(short = 'p', long, default_value_t = DEFAULT_PORT)]
+    port: u16,
+    /// print the disk cache statistics when finished
+    #[arg(long)]
+    stats: bool,
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
     let output = match cli.command {
-        Command::Decode { value } => decode_command(value),
+        // the values are printed as soon as they are decoded
+        Command::Decode { value: None, file: None, binary, strict, query } => return decode_stdin(binary, strict, query.as_deref()).await,
+        Command::Decode { value, file, binary, strict, query } => decode_command(value, file.as_deref(), binary, strict, query.as_deref()).await,
+        // bencode is binary, it's written as is, without a new line
+        Command::Encode { value } => return encode_command(value).await,
         Command::Info { path } => info_command(&path).await,
         Command::Peers { path } => peers_command(&path).await,
         Command::Handshake { torrent_path, peer_socket } => handshake_command(&torrent_path, &peer_socket).await,
         Command::DownloadPiece { save_location, torrent_path, piece } => download_piece_command(&torrent_path, piece, &save_location).await,
-        Command::Download { save_location, torrent_path } => download_command(&torrent_path, &save_location).await,
+        // the data itself is written to stdout
+        Command::Download { torrent_path, options, .. } if options.storage == StorageKind::Memory => return download_to_stdout(&torrent_path, &options).await,
+        Command::Download { save_location, torrent_path, options } => download_command(&torrent_path, save_location.as_deref(), &options).await,
+        Command::Seed { torrent_path, data_path, port, move_to, stats } => seed_command(&torrent_path, &data_path, port, move_to.as_deref(), stats).await,
+        Command::Verify { torrent_path, data_path, json } => verify_command(&torrent_path, &data_path, json).await,
     }?;
     println!("{output}");
     Ok(())
 }
 
-fn decode_command(value: String) -> anyhow::Result<String> {
-    let value = decode_value_str(&value)?;
-    let json = json_encode_value(value)?;
+async fn decode_command(value: Option<String>, file: Option<&str>, binary: BinaryFormat, strict: bool, query: Option<&str>) -> anyhow::Result<String> {
+    let input = match (file, value) {
+        (Some(file), _) => tokio::fs::read(file).await.context(format!("failed to read {file}"))?,
+        (None, Some(value)) => value.into_bytes(),
+        (None, None) => unreachable!("stdin is decoded by decode_stdin"),
+    };
+    let value = if strict {
+        decode_value_with_options(&input, DecodeOptions{ mode: DecodeMode::Strict, ..Default::default() })?
+    } else {
+        decode_value(&input)?
+    };
+    render_value(&value, binary, query)
+}
+
+/// Decodes the values as soon as they are received, each one is written on a separate line
+async fn decode_stdin(binary: BinaryFormat, strict: bool, query: Option<&str>) -> anyhow::Result<()> {
+    let mode = if strict { DecodeMode::Strict } else { DecodeMode::Lenient };
+    let mut stream = StreamDecoder::new(DecodeOptions{ mode, ..Default::default() });
+    let mut stdin = tokio::io::stdin();
+    let mut chunk = vec![0; STDIN_CHUNK_SIZE];
+    loop {
+        let length = stdin.read(&mut chunk).await.context("failed to read stdin")?;
+        if length == 0 {
+            break;
+        }
+        stream.feed(&chunk[..length]);
+        while let Some(value) = stream.next_value()? {
+            println!("{}", render_value(&value, binary, query)?);
+        }
+    }
+    if !stream.remainder().is_empty() {
+        bail!("stdin ends in the middle of a value");
+    }
+    Ok(())
+}
+
+fn render_value(value: &Value, binary: BinaryFormat, query: Option<&str>) -> anyhow::Result<String> {
+    let json = match query {
+        Some(query) => json_encode_value(&query_value(value, query)?, binary),
+        None => json_encode_value(value, binary),
+    };
     Ok(json)
 }
 
+async fn encode_command(value: Option<String>) -> anyhow::Result<()> {
+    let input = match value {
+        Some(value) => value,
+        None => {
+            let mut input = String::new();
+            tokio::io::stdin().read_to_string(&mut input).await.context("failed to read stdin")?;
+            input
+        },
+    };
+    let json = serde_json::from_str::<serde_json::Value>(&input).context("invalid json")?;
+    let mut output = vec![];
+    json_to_bencode(&mut output, &json)?;
+    let mut stdout = tokio::io::stdout();
+    stdout.write_all(&output).await.context("failed to write stdout")?;
+    stdout.flush().await.context("failed to write stdout")?;
+    Ok(())
+}
+
 async fn info_command(path: &str) -> anyhow::Result<String> {
     let torrent = parse_torrent_from_file(path).await?;
     let Torrent{ announce, info } = torrent;
@@ -107,9 +282,9 @@ Piece Hashes:
 
 async fn peers_command(path: &str) -> anyhow::Result<String> {
     let torrent = parse_torrent_from_file(path).await?;
-    let peers = request_peers(&torrent).await?;
+    let peers = request_peers(&torrent, DEFAULT_PORT, torrent.info.get_length()).await?;
     let peers = peers.peers.iter().map(|addr| addr.to_string()).collect::<Vec<_>>();
-    let res = format!("{}", peers.join("\n"));
+    let res = peers.join("\n");
     Ok(res)
 }
 
@@ -117,7 +292,7 @@ async fn handshake_command(path: &str, socket: &str) -> anyhow::Result<String> {
     let socket = SocketAddrV4::from_str(socket).context("failed to parse socket addr")?;
     let torrent = parse_torrent_from_file(path).await?;
     let info_hash = torrent.info.get_info_hash()?;
-    let peer = init_peer(info_hash, &socket).await?;
+    let peer = connect_peer(info_hash, torrent.info.pieces.len() as u32, &socket).await?;
     let peer_id = hex::encode(peer.peer_id);
     let output = format!("Peer ID: {peer_id}");
     Ok(output)
@@ -126,79 +301,482 @@ async fn handshake_command(path: &str, socket: &str) -> anyhow::Result<String> {
 async fn download_piece_command(torrent_path: &str, piece: u32, save_location: &str) -> anyhow::Result<String> {
     let torrent = parse_torrent_from_file(torrent_path).await?;
     let piece_info = torrent.info.get_piece_info(piece)?;
-    let peers = request_peers(&torrent).await?;
+    let peers = request_peers(&torrent, DEFAULT_PORT, torrent.info.get_length()).await?;
     let info_hash = torrent.info.get_info_hash()?;
-    let mut peer = init_peer(info_hash, &peers.peers[0]).await?;
+    let pieces_count = torrent.info.pieces.len() as u32;
+    let mut peer = init_peer(info_hash, pieces_count, &peers.peers[0], &vec![0; bitfield_length(pieces_count)]).await?;
     let piece_data = peer.download_piece(piece_info).await?;
     let mut save_file = File::create(save_location).await.context("failed to create file")?;
-    save_file.write(&piece_data).await?;
+    save_file.write_all(&piece_data).await?;
     let ret = format!("Piece {piece} downloaded to {save_location}");
     Ok(ret)
 }
 
-async fn download_command(torrent_path: &str, save_location: &str) -> anyhow::Result<String> {
+async fn download_command(torrent_path: &str, save_location: Option<&str>, options: &DownloadOptions) -> anyhow::Result<String> {
+    let DownloadOptions{ storage: storage_kind, mmap, preallocate: preallocation, ref incomplete_dir, ref skip, port, stats } = *options;
     let torrent = parse_torrent_from_file(torrent_path).await?;
-    if !torrent.info.is_single_file() {
-        bail!("only single file torrents are supported");
+    let save_location = save_location.map(PathBuf::from).unwrap_or_else(|| torrent.info.safe_name());
+    let data_path = save_location.as_path();
+    let mut files = torrent.info.get_files_info(data_path);
+    let mut skipped = vec![false; files.len()];
+    for file_index in skip {
+        let Some(skipped) = skipped.get_mut(*file_index) else {
+            bail!("file index {file_index} is out of range, the torrent has {} files", files.len());
+        };
+        *skipped = true;
     }
+    if mmap && !skip.is_empty() {
+        bail!("files can not be skipped with memory mapped storage");
+    }
+    if storage_kind != StorageKind::File && (mmap || incomplete_dir.is_some()) {
+        bail!("--mmap and --incomplete-dir can only be used with the file storage");
+    }
+    // the resume file stays next to the final location, the file modification times are kept when the files are renamed
+    let resume_path = resume_file_path(data_path);
+    if storage_kind == StorageKind::PieceDir {
+        let storage = Arc::new(PieceDirStorage::new(save_location.clone()));
+        // the modification time of the directory changes when the piece files are added or replaced
+        download_to_storage(&torrent, Some(resume_path), vec![save_location.clone()], &skipped, storage, port).await?;
+        let ret = format!("Downloaded {torrent_path} pieces to {}", save_location.display());
+        return Ok(ret);
+    }
+
+    let final_paths = files.iter().map(|file| file.path.clone()).collect::<Vec<_>>();
+    let staged_paths = staging_paths(&final_paths, data_path, incomplete_dir.as_deref().map(Path::new))?;
+    // completed data is kept in place, so that it's only rechecked
+    let staged = wanted(&staged_paths, &skipped).iter().any(|path| path.exists()) || !wanted(&final_paths, &skipped).iter().all(|path| path.exists());
+    if staged {
+        for (file, path) in files.iter_mut().zip(&staged_paths) {
+            file.path = path.clone();
+        }
+    }
+    let data_paths = files.iter().map(|file| file.path.clone()).collect::<Vec<_>>();
+    let part_file = PartFile::new(partfile_path(data_path));
+    // the skipped files do not exist, the part file is tracked instead
+    let mut resume_data_paths = wanted(&data_paths, &skipped);
+    if skipped.contains(&true) {
+        resume_data_paths.push(part_file.path().to_path_buf());
+    }
+    if mmap {
+        let storage = Arc::new(open_mmap_storage(files, preallocation)?);
+        download_to_storage(&torrent, Some(resume_path), resume_data_paths, &skipped, storage, port).await?;
+    } else {
+        let storage = FileStorage::new(files)
+            .with_preallocation(preallocation)
+            .with_part_file(part_file, skipped.clone(), torrent.info.get_all_pieces_info().collect());
+        let storage = Arc::new(storage);
+        download_to_storage(&torrent, Some(resume_path), resume_data_paths, &skipped, storage.clone(), port).await?;
+        if stats {
+            eprintln!("disk {}", storage.cache_stats());
+        }
+    }
+    if staged {
+        // the storage is flushed and closed at this point, and all pieces are verified
+        let lengths = torrent.info.get_files_info(data_path).into_iter().map(|file| file.length).collect::<Vec<_>>();
+        move_files(&wanted(&data_paths, &skipped), &wanted(&final_paths, &skipped), &wanted(&lengths, &skipped)).await?;
+    }
+    let ret = format!("Downloaded {torrent_path} to {}", save_location.display());
+    Ok(ret)
+}
+
+/// Downloads into memory, and writes the data of all files to stdout one after another
+async fn download_to_stdout(torrent_path: &str, options: &DownloadOptions) -> anyhow::Result<()> {
+    if options.mmap || options.incomplete_dir.is_some() || !options.skip.is_empty() {
+        bail!("--mmap, --incomplete-dir and --skip can not be used with the memory storage");
+    }
+    let torrent = parse_torrent_from_file(torrent_path).await?;
+    let skipped = vec![false; torrent.info.get_files_info(Path::new("")).len()];
+    let storage = Arc::new(MemoryStorage::new(torrent.info.get_length()));
+    download_to_storage(&torrent, None, vec![], &skipped, storage.clone(), options.port).await?;
+    let mut stdout = tokio::io::stdout();
+    stdout.write_all(&storage.take_data()).await.context("failed to write stdout")?;
+    stdout.flush().await.context("failed to write stdout")?;
+    Ok(())
+}
+
+/// Leaves only the items of the files that are not skipped
+fn wanted<T: Clone>(items: &[T], skipped: &[bool]) -> Vec<T> {
+    items.iter().zip(skipped).filter(|(_, skipped)| !**skipped).map(|(item, _)| item.clone()).collect()
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn open_mmap_storage(files: Vec<FileInfo>, preallocation: PreallocationMode) -> anyhow::Result<MmapStorage> {
+    MmapStorage::open(files, preallocation)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn open_mmap_storage(_files: Vec<FileInfo>, _preallocation: PreallocationMode) -> anyhow::Result<FileStorage> {
+    bail!("memory mapped storage is not supported on this platform");
+}
+
+/// Shared between the download connections
+struct DownloadState<S> {
+    info_hash: [u8; HASH_RAW_LENGTH],
+    pieces_count: u32,
+    /// pieces that were completed before the download started, the others are announced with have messages
+    bitfield: Vec<u8>,
+    storage: Arc<S>,
+    resume: Arc<ResumeWriter>,
+    pieces: std::sync::Mutex<Vec<PieceInfo>>,
+    /// count of the pieces that are downloaded in this session
+    wanted_count: usize,
+    /// pieces that were downloaded in this session, in the order of completion
+    completed_pieces: std::sync::Mutex<Vec<u32>>,
+    /// set when all wanted pieces are written, so that the idle connections could stop waiting
+    finished: watch::Sender<bool>,
+}
+impl<S> DownloadState<S> {
+    fn new(info: &TorrentInfo, info_hash: [u8; HASH_RAW_LENGTH], bitfield: Vec<u8>, storage: Arc<S>, resume: Arc<ResumeWriter>, pieces: Vec<PieceInfo>) -> Self {
+        let (finished, _) = watch::channel(pieces.is_empty());
+        Self {
+            info_hash,
+            pieces_count: info.pieces.len() as u32,
+            bitfield,
+            storage,
+            resume,
+            wanted_count: pieces.len(),
+            pieces: std::sync::Mutex::new(pieces),
+            completed_pieces: std::sync::Mutex::new(vec![]),
+            finished,
+        }
+    }
+
+    /// Should be called only after the piece is written to the storage
+    fn complete_piece(&self, index: u32) {
+        self.resume.set_piece(index);
+        let mut completed_pieces = self.completed_pieces.lock().expect("poisoned lock");
+        completed_pieces.push(index);
+        if completed_pieces.len() == self.wanted_count {
+            self.finished.send_replace(true);
+        }
+    }
+
+    /// Completes when all wanted pieces are written
+    async fn wait_finished(&self) {
+        let mut finished = self.finished.subscribe();
+        // the sender is kept in the state, so the wait can not fail
+        let _ = finished.wait_for(|finished| *finished).await;
+    }
+}
+
+async fn download_to_storage<S: Storage + 'static>(
+    torrent: &Torrent,
+    resume_path: Option<PathBuf>,
+    data_paths: Vec<PathBuf>,
+    skipped: &[bool],
+    storage: Arc<S>,
+    port: u16,
+) -> anyhow::Result<()> {
     let info_hash = torrent.info.get_info_hash()?;
-    let peers = request_peers(&torrent).await?;
+    storage.preallocate().await?;
+    let resume = match resume_path {
+        Some(resume_path) => {
+            let bitfield = load_verified_pieces(storage.as_ref(), &torrent.info, &info_hash, &resume_path, &data_paths).await?;
+            ResumeWriter::new(resume_path, data_paths, info_hash, bitfield)
+        },
+        // the storages without a resume file do not keep the data between the runs, so they start empty
+        None => ResumeWriter::without_file(info_hash, vec![0; bitfield_length(torrent.info.pieces.len() as u32)]),
+    };
+    let bitfield = resume.bitfield();
+    let resume = Arc::new(resume);
 
-    let file_length = torrent.info.get_length(); // this is correct only for single-file torrents!!!
-    let file = create_file_with_reserved_size(save_location, file_length as u64).await?;
-    let file = Arc::new(Mutex::new(file));
+    // only the positions of the files are needed
+    let files = torrent.info.get_files_info(Path::new(""));
+    let pieces = torrent.info
+        .get_all_pieces_info()
+        .filter(|piece| !piece_exists(piece.index, &bitfield) && is_piece_wanted(&files, skipped, piece))
+        .collect::<Vec<_>>();
+    if pieces.is_empty() {
+        return resume.save(storage.as_ref()).await;
+    }
+    let listener = PeerListener::bind(port, MAX_CONNECTIONS).await?;
+    let listener = Arc::new(listener);
+    let left = pieces.iter().map(|piece| piece.length).sum();
+    let state = DownloadState::new(&torrent.info, info_hash, bitfield, storage, resume, pieces);
+    let incoming = listener.add_torrent(info_hash, state.pieces_count, MAX_CONNECTIONS_PER_TORRENT);
+    let listener_task = tokio::spawn(listener.clone().run());
+    let result = async {
+        let peers = request_peers(torrent, listener.port(), left).await?.peers;
+        download_from_peers(Arc::new(state), peers, &listener, incoming).await
+    }.await;
+    listener_task.abort();
+    result
+}
 
-    let pieces = torrent.info.get_all_pieces_info().collect::<Vec<_>>();
-    let pieces = Arc::new(std::sync::Mutex::new(pieces));
-    let pieces_count = torrent.info.pieces.len();
-    let peers_count = peers.peers.len();
-    let threads_count = cmp::min(pieces_count, peers_count);
+/// Downloads the pieces from the peers and from the peers that connect to us, until all of them are written
+async fn download_from_peers<S: Storage + 'static>(
+    state: Arc<DownloadState<S>>,
+    peers: Vec<SocketAddrV4>,
+    listener: &PeerListener,
+    mut incoming: mpsc::Receiver<IncomingPeer>,
+) -> anyhow::Result<()> {
+    let (info_hash, pieces_count) = (state.info_hash, state.pieces_count);
+    let threads_count = cmp::min(state.wanted_count, peers.len());
     let mut join_set = JoinSet::new();
-    for thread_no in 0..threads_count {
-        let socket = peers.peers[thread_no];
-        let file = file.clone();
-        let pieces = pieces.clone();
+    for socket in peers.into_iter().take(threads_count) {
+        let Ok(permit) = listener.try_acquire_permit(&info_hash) else {
+            break;
+        };
+        let state = state.clone();
         join_set.spawn(async move {
-            let mut peer = init_peer(info_hash, &socket).await?;
-            while let Some(piece_info) = pop_mutex_vec(pieces.deref()) {
-                let file_start_pos = piece_info.file_start_pos;
-                // todo: maybe download blocks of the same piece in parallel too
-                let piece_data = peer.download_piece(piece_info).await?;
-                // todo: maybe dump data into multiple files, and then assemble
-                let mut file_guard = file.lock().await;
-                file_guard.seek(SeekFrom::Start(file_start_pos as u64)).await.context("failed to seek file for write")?;
-                file_guard.write(&piece_data).await.context("failed to write data to file")?;
-                drop(file_guard);
+            let result = async {
+                let peer = tokio::select! {
+                    peer = init_peer(info_hash, pieces_count, &socket, &state.bitfield) => peer?,
+                    _ = state.wait_finished() => return Ok(()),
+                };
+                download_from_peer(peer, &state).await
+            }.await;
+            drop(permit);
+            result
+        });
+    }
+
+    let (storage, resume) = (state.storage.as_ref(), state.resume.as_ref());
+    let mut save_interval = tokio::time::interval(RESUME_SAVE_INTERVAL);
+    // the first tick completes immediately
+    save_interval.tick().await;
+    let result = loop {
+        tokio::select! {
+            _ = state.wait_finished() => break Ok(()),
+            Some(incoming) = incoming.recv() => {
+                let state = state.clone();
+                join_set.spawn(async move {
+                    let IncomingPeer{ mut peer, permit } = incoming;
+                    let socket = peer.socket_string();
+                    let result = async {
+                        tokio::select! {
+                            result = peer.start_download(&state.bitfield) => result?,
+                            _ = state.wait_finished() => return Ok(()),
+                        }
+                        download_from_peer(peer, &state).await
+                    }.await;
+                    drop(permit);
+                    // the peers that have connected to us should not stop the download
+                    if let Err(error) = result {
+                        eprintln!("stopped downloading from {socket}: {error:#}");
+                    }
+                    Ok(())
+                });
+            },
+            result = join_set.join_next() => {
+                let Some(result) = result else {
+                    break Ok(());
+                };
+                let result = result.context("join error").and_then(|result| result);
+                if result.is_err() {
+                    break result;
+                }
+            },
+            _ = save_interval.tick() => {
+                if let Err(error) = resume.save(storage).await {
+                    eprintln!("failed to save resume file: {error:#}");
+                }
+            },
+            result = tokio::signal::ctrl_c() => {
+                result.context("failed to wait for ctrl-c")?;
+                break Err(anyhow!("download was interrupted"));
+            },
+        }
+    };
+    // no more peers are accepted after this point
+    drop(incoming);
+    // the pieces that are being written right now are not in the bitfield yet, so it's ok to stop the workers at any point
+    join_set.abort_all();
+    while join_set.join_next().await.is_some() {}
+    // the download error is more important, a failed save only leads to a recheck on the next start
+    if let Err(error) = resume.save(storage).await {
+        eprintln!("failed to save resume file: {error:#}");
+    }
+    result?;
+    let missing_count = state.pieces.lock().expect("poisoned lock").len();
+    if missing_count > 0 {
+        bail!("download is incomplete, {missing_count} pieces could not be downloaded from any peer");
+    }
+    Ok(())
+}
+
+/// Downloads the pieces from the peer until there are none left that it has
+async fn download_from_peer<S: Storage>(mut peer: Peer, state: &DownloadState<S>) -> anyhow::Result<()> {
+    let mut announced_count = 0;
+    let mut rejected_count = 0;
+    loop {
+        let Some(piece_info) = pop_piece(&state.pieces, &peer) else {
+            if state.pieces.lock().expect("poisoned lock").is_empty() {
+                break;
+            }
+            // the peer has none of the remaining pieces yet, it's only dropped if it stays idle for too long
+            tokio::select! {
+                result = peer.wait_for_new_pieces() => {
+                    if result.is_err() {
+                        break;
+                    }
+                },
+                _ = state.wait_finished() => break,
+            }
+            continue;
+        };
+        // let the peer know about the pieces that were downloaded by the other connections
+        let new_pieces = state.completed_pieces.lock().expect("poisoned lock")[announced_count..].to_vec();
+        announced_count += new_pieces.len();
+        for piece_index in new_pieces {
+            peer.send_have(piece_index).await?;
+        }
+
+        let piece_index = piece_info.index;
+        // todo: maybe download blocks of the same piece in parallel too
+        let piece_data = match peer.download_piece(piece_info.clone()).await {
+            Ok(piece_data) => piece_data,
+            Err(error) if error.is::<PieceRejected>() => {
+                // give the piece to the other peers right away, they will take it before this one gets back to it
+                state.pieces.lock().expect("poisoned lock").insert(0, piece_info);
+                rejected_count += 1;
+                if rejected_count >= MAX_REJECTED_PIECES {
+                    break;
+                }
+                continue;
+            },
+            Err(error) => return Err(error),
+        };
+        rejected_count = 0;
+        state.storage.write_piece(&piece_info, &piece_data).await?;
+        state.complete_piece(piece_index);
+    }
+    Ok(())
+}
+
+async fn seed_command(torrent_path: &str, data_path: &str, port: u16, move_to: Option<&str>, stats: bool) -> anyhow::Result<String> {
+    let torrent = parse_torrent_from_file(torrent_path).await?;
+    let info_hash = torrent.info.get_info_hash()?;
+    let storage = FileStorage::new(torrent.info.get_files_info(Path::new(data_path)));
+    let seed = SeedData::open(torrent.info.clone(), storage).await?;
+    let seed = Arc::new(seed);
+    let move_task = move_to.map(|move_to| {
+        let seed = seed.clone();
+        let new_paths = torrent.info.get_files_info(Path::new(move_to)).into_iter().map(|file| file.path).collect();
+        let move_to = move_to.to_string();
+        // the peers keep being served while the files are moved, each file is only locked while it's moved
+        tokio::spawn(async move {
+            match seed.storage().move_files(new_paths).await {
+                Ok(()) => eprintln!("moved data to {move_to}"),
+                Err(error) => eprintln!("failed to move data to {move_to}: {error:#}"),
             }
-            Ok(())
+        })
+    });
+    let choker = Arc::new(SharedChoker::new(ChokerMode::Seeding));
+    let choker_task = {
+        let choker = choker.clone();
+        tokio::spawn(async move { choker.run().await })
+    };
+
+    let listener = PeerListener::bind(port, MAX_CONNECTIONS).await?;
+    let listener = Arc::new(listener);
+    let mut incoming = listener.add_torrent(info_hash, seed.pieces_count(), MAX_CONNECTIONS_PER_TORRENT);
+    let mut listener_task = tokio::spawn(listener.clone().run());
+    let peers = request_peers(&torrent, listener.port(), 0).await?;
+
+    let mut join_set = JoinSet::new();
+    for socket in peers.peers {
+        let Ok(permit) = listener.try_acquire_permit(&info_hash) else {
+            break;
+        };
+        let seed = seed.clone();
+        let choker = choker.clone();
+        join_set.spawn(async move {
+            let result = async {
+                let mut peer = connect_peer(info_hash, seed.pieces_count(), &socket).await?;
+                peer.start_upload(&seed.bitfield).await?;
+                peer.serve(&seed, &choker).await
+            }.await;
+            drop(permit);
+            (socket.to_string(), result)
         });
     }
 
-    while let Some(result) = join_set.join_next().await {
-        // all futures should be dropped when JoinSet is dropped, so it's ok to just exit
-        let result: anyhow::Result<()> = result.context("join error")?;
-        result?
+    loop {
+        tokio::select! {
+            Some(incoming) = incoming.recv() => {
+                let seed = seed.clone();
+                let choker = choker.clone();
+                join_set.spawn(async move {
+                    let IncomingPeer{ mut peer, permit } = incoming;
+                    let socket = peer.socket_string();
+                    let result = async {
+                        peer.start_upload(&seed.bitfield).await?;
+                        peer.serve(&seed, &choker).await
+                    }.await;
+                    drop(permit);
+                    (socket, result)
+                });
+            },
+            Some(result) = join_set.join_next() => {
+                let (socket, result) = result.context("join error")?;
+                if let Err(error) = result {
+                    // the peer has disconnected, it should not stop serving the other peers
+                    eprintln!("stopped seeding to {socket}: {error:#}");
+                }
+            },
+            result = &mut listener_task => {
+                result.context("join error")?;
+                break;
+            },
+            result = tokio::signal::ctrl_c() => {
+                result.context("failed to wait for ctrl-c")?;
+                break;
+            },
+        }
+    }
+    listener_task.abort();
+    choker_task.abort();
+    if let Some(move_task) = move_task {
+        // an interrupted move would leave the data split between the locations
+        move_task.await.context("join error")?;
+    }
+    if stats {
+        eprintln!("disk {}", seed.storage().cache_stats());
     }
 
-    let ret = format!("Downloaded {torrent_path} to {save_location}");
+    let ret = format!("Finished seeding {torrent_path} from {data_path}");
     Ok(ret)
 }
 
-async fn create_file_with_reserved_size(path: impl AsRef<Path>, file_size: u64) -> anyhow::Result<File> {
-    let mut file = File::create(path).await?;
-    file.seek(SeekFrom::Start(file_size - 1)).await.context("failed to seek file for reserve")?;
-    file.write(&[0]).await.context("failed to write file for reserve")?;
-    Ok(file)
+async fn verify_command(torrent_path: &str, data_path: &str, json: bool) -> anyhow::Result<String> {
+    let torrent = parse_torrent_from_file(torrent_path).await?;
+    let data_path = PathBuf::from(data_path);
+    let report = tokio::task::spawn_blocking(move || verify_data(&torrent.info, &data_path))
+        .await
+        .context("join error")??;
+    if json {
+        report.to_json()
+    } else {
+        Ok(report.to_text())
+    }
 }
 
-fn pop_mutex_vec<T>(mutex_vec: &std::sync::Mutex<Vec<T>>) -> Option<T> {
-    mutex_vec.lock().expect("poisoned lock").pop()
+/// Pieces that overlap the skipped files are still downloaded if they also overlap the wanted ones
+fn is_piece_wanted(files: &[FileInfo], skipped: &[bool], piece: &PieceInfo) -> bool {
+    file_segments(files, piece.file_start_pos as u64, piece.length as u64)
+        .iter()
+        .any(|segment| !skipped[segment.file_index])
+}
+
+/// Takes a piece that the peer has, preferring the ones that it has suggested
+fn pop_piece(pieces: &std::sync::Mutex<Vec<PieceInfo>>, peer: &Peer) -> Option<PieceInfo> {
+    let mut pieces = pieces.lock().expect("poisoned lock");
+    let suggested = peer.suggested_pieces();
+    let pos = pieces
+        .iter()
+        .position(|piece| suggested.contains(&piece.index) && peer.has_piece(piece.index))
+        .or_else(|| pieces.iter().rposition(|piece| peer.has_piece(piece.index)));
+    pos.map(|pos| pieces.remove(pos))
 }
 
 #[cfg(test)]
 mod test {
     use std::io;
+    use std::time::Duration;
     use sha1::{Sha1, Digest};
+    use crate::peer::test::{spawn_idle_peer, spawn_seeder};
+    use crate::seed::test::{create_seed, get_data, PIECE_LENGTH};
     use super::*;
 
     #[tokio::test]
@@ -238,21 +816,82 @@ f00d937a0213df1982bc8d097227ad9e909acc17";
 
     #[tokio::test]
     async fn test_download_piece() -> anyhow::Result<()> {
-        let output = download_piece_command("sample.torrent", 0, "download/test-piece-0").await?;
-        let expected = "Piece 0 downloaded to download/test-piece-0";
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("test-piece-0").to_string_lossy().to_string();
+        let output = download_piece_command("sample.torrent", 0, &file_path).await?;
+        let expected = format!("Piece 0 downloaded to {file_path}");
         assert_eq!(expected, output);
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_idle_peer_does_not_block_download() -> anyhow::Result<()> {
+        let data = get_data(PIECE_LENGTH as usize * 2 + 100);
+        let seed = create_seed(&data).await?;
+        let pieces = data
+            .chunks(PIECE_LENGTH as usize)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let info = TorrentInfo::new_single_file("test", data.len() as u32, PIECE_LENGTH, pieces);
+        let info_hash = [1; HASH_RAW_LENGTH];
+        let pieces_count = seed.pieces_count();
+        // the idle peer comes first, so that it is connected before the seeder finishes
+        let peers = vec![spawn_idle_peer(pieces_count).await?, spawn_seeder(seed).await?];
+
+        let dir = tempfile::tempdir()?;
+        let storage = Arc::new(MemoryStorage::new(data.len() as u32));
+        storage.preallocate().await?;
+        let bitfield = vec![0; bitfield_length(pieces_count)];
+        let resume = ResumeWriter::new(dir.path().join("test.resume"), vec![], info_hash, bitfield.clone());
+        let pieces = info.get_all_pieces_info().collect();
+        let state = DownloadState::new(&info, info_hash, bitfield, storage.clone(), Arc::new(resume), pieces);
+        let listener = PeerListener::bind(0, MAX_CONNECTIONS).await?;
+        let incoming = listener.add_torrent(info_hash, pieces_count, MAX_CONNECTIONS_PER_TORRENT);
+
+        let download = download_from_peers(Arc::new(state), peers, &listener, incoming);
+        tokio::time::timeout(Duration::from_secs(10), download).await.context("download should not wait for the idle peer")??;
+        for piece in info.get_all_pieces_info() {
+            assert_eq!(Some(piece.hash), storage.hash_piece(&piece).await?, "piece {} should be downloaded", piece.index);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_storage_options_are_checked() -> anyhow::Result<()> {
+        let options = DownloadOptions {
+            storage: StorageKind::PieceDir,
+            mmap: false,
+            preallocate: PreallocationMode::Sparse,
+            incomplete_dir: Some("incomplete".to_string()),
+            skip: vec![],
+            port: 0,
+            stats: false,
+        };
+        assert!(download_command("sample.torrent", Some("test"), &options).await.is_err(), "incomplete dir should be rejected with the piece dir storage");
+        let options = DownloadOptions{ storage: StorageKind::Memory, incomplete_dir: None, skip: vec![0], ..options };
+        assert!(download_to_stdout("sample.torrent", &options).await.is_err(), "skipped files should be rejected with the memory storage");
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_download() -> anyhow::Result<()> {
         // tests are configured to be run in 1 thread, because there are errors when communicating with the same peer in parallel
-        let file_path = "download/test";
-        let output = download_command("sample.torrent", file_path).await?;
-        let expected = "Downloaded sample.torrent to download/test";
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("test").to_string_lossy().to_string();
+        let options = DownloadOptions {
+            storage: StorageKind::File,
+            mmap: false,
+            preallocate: PreallocationMode::Sparse,
+            incomplete_dir: None,
+            skip: vec![],
+            port: 0,
+            stats: false,
+        };
+        let output = download_command("sample.torrent", Some(&file_path), &options).await?;
+        let expected = format!("Downloaded sample.torrent to {file_path}");
         assert_eq!(expected, output);
 
-        let mut file = std::fs::File::open(file_path)?;
+        let mut file = std::fs::File::open(&file_path)?;
         let mut hasher = Sha1::new();
         io::copy(&mut file, &mut hasher)?;
         let actual_hash = hasher.finalize();
@@ -0,0 +1,262 @@
+use std::ffi::{c_int, c_long, c_void};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::ptr::NonNull;
+use std::sync::RwLock;
+use anyhow::{bail, Context};
+use crate::hasher::HashPool;
+use crate::preallocate::{check_free_space, preallocate_file, PreallocationMode};
+use crate::torrent::{file_segments, FileInfo, PieceInfo, HASH_RAW_LENGTH};
+use crate::storage::Storage;
+
+// there are no bindings for these in std, and the values are the same on linux and macos, except for the noted ones
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const MAP_SHARED: c_int = 1;
+#[cfg(target_os = "linux")]
+const MS_SYNC: c_int = 4;
+#[cfg(target_os = "macos")]
+const MS_SYNC: c_int = 0x10;
+
+/// pieces that share a lock can not be accessed at the same time, but the downloads only write a few pieces at once
+const PIECE_LOCKS: usize = 64;
+
+extern "C" {
+    fn mmap(addr: *mut c_void, length: usize, prot: c_int, flags: c_int, fd: c_int, offset: c_long) -> *mut c_void;
+    fn munmap(addr: *mut c_void, length: usize) -> c_int;
+    fn msync(addr: *mut c_void, length: usize, flags: c_int) -> c_int;
+}
+
+enum FileMap {
+    Mapped {
+        ptr: NonNull<u8>,
+        length: usize,
+        /// the mapping stays valid after the file is closed, but the file is kept to make it explicit
+        _file: File,
+    },
+    /// files that can not be mapped are accessed with positioned I/O
+    Unmapped(File),
+    /// empty files can not be mapped, and have no data anyway
+    Empty,
+}
+// the mapping is not tied to a thread. MmapStorage locks the piece for every access, so a range is never
+// written while it is read or written by someone else
+unsafe impl Send for FileMap {}
+unsafe impl Sync for FileMap {}
+
+impl FileMap {
+    fn open(info: &FileInfo, preallocation: PreallocationMode, try_mapping: bool) -> anyhow::Result<Self> {
+        let path = info.path.display();
+        if let Some(parent) = info.path.parent() {
+            std::fs::create_dir_all(parent).context(format!("failed to create directory {}", parent.display()))?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&info.path)
+            .context(format!("failed to open {path}"))?;
+        preallocate_file(&file, info.length as u64, preallocation).context(format!("failed to preallocate {path}"))?;
+        if info.length == 0 {
+            return Ok(Self::Empty);
+        }
+        if !try_mapping {
+            return Ok(Self::Unmapped(file));
+        }
+
+        let length = info.length as usize;
+        let ptr = unsafe {
+            mmap(std::ptr::null_mut(), length, PROT_READ | PROT_WRITE, MAP_SHARED, file.as_raw_fd(), 0)
+        };
+        // MAP_FAILED is -1
+        if ptr as isize == -1 {
+            eprintln!("failed to map {path}, using file I/O instead: {}", io::Error::last_os_error());
+            return Ok(Self::Unmapped(file));
+        }
+        let ptr = NonNull::new(ptr as *mut u8).expect("mapping should not be null");
+        Ok(Self::Mapped{ ptr, length, _file: file })
+    }
+
+    fn read(&self, pos: u64, buffer: &mut [u8]) -> anyhow::Result<()> {
+        match self {
+            Self::Mapped { ptr, length, .. } => {
+                check_range(pos, buffer.len(), *length)?;
+                unsafe { ptr.as_ptr().add(pos as usize).copy_to_nonoverlapping(buffer.as_mut_ptr(), buffer.len()) };
+            },
+            Self::Unmapped(file) => file.read_exact_at(buffer, pos).context("failed to read file")?,
+            Self::Empty => check_range(pos, buffer.len(), 0)?,
+        }
+        Ok(())
+    }
+
+    fn write(&self, pos: u64, data: &[u8]) -> anyhow::Result<()> {
+        match self {
+            Self::Mapped { ptr, length, .. } => {
+                check_range(pos, data.len(), *length)?;
+                unsafe { ptr.as_ptr().add(pos as usize).copy_from_nonoverlapping(data.as_ptr(), data.len()) };
+            },
+            Self::Unmapped(file) => file.write_all_at(data, pos).context("failed to write data to file")?,
+            Self::Empty => check_range(pos, data.len(), 0)?,
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        match self {
+            Self::Mapped { ptr, length, .. } => {
+                let result = unsafe { msync(ptr.as_ptr() as *mut c_void, *length, MS_SYNC) };
+                if result != 0 {
+                    return Err(io::Error::last_os_error()).context("failed to sync mapped file");
+                }
+                Ok(())
+            },
+            Self::Unmapped(file) => file.sync_data().context("failed to sync file"),
+            Self::Empty => Ok(()),
+        }
+    }
+}
+
+impl Drop for FileMap {
+    fn drop(&mut self) {
+        if let Self::Mapped { ptr, length, .. } = self {
+            unsafe { munmap(ptr.as_ptr() as *mut c_void, *length) };
+        }
+    }
+}
+
+fn check_range(pos: u64, length: usize, file_length: usize) -> anyhow::Result<()> {
+    if pos + length as u64 > file_length as u64 {
+        bail!("range at {pos} with length {length} is out of the file of length {file_length}");
+    }
+    Ok(())
+}
+
+/// Torrent files are memory mapped, so that the pieces could be read and written without copying them through file handles.
+/// The files should not be truncated by anyone else while they are mapped
+pub(crate) struct MmapStorage {
+    files: Vec<FileInfo>,
+    maps: Vec<FileMap>,
+    /// a piece is locked by `index % PIECE_LOCKS`, so that it's not read while it's written
+    piece_locks: Vec<RwLock<()>>,
+}
+impl MmapStorage {
+    /// Files are created and resized when the storage is opened, the existing data is kept.
+    /// Files have to be resized to be mapped, so no preallocation works the same as the sparse one
+    pub fn open(files: Vec<FileInfo>, preallocation: PreallocationMode) -> anyhow::Result<Self> {
+        Self::open_internal(files, preallocation, true)
+    }
+
+    #[cfg(test)]
+    fn open_without_mapping(files: Vec<FileInfo>) -> anyhow::Result<Self> {
+        Self::open_internal(files, PreallocationMode::Sparse, false)
+    }
+
+    fn open_internal(files: Vec<FileInfo>, preallocation: PreallocationMode, try_mapping: bool) -> anyhow::Result<Self> {
+        check_free_space(&files)?;
+        let preallocation = match preallocation {
+            PreallocationMode::None => PreallocationMode::Sparse,
+            preallocation => preallocation,
+        };
+        let maps = files
+            .iter()
+            .map(|info| FileMap::open(info, preallocation, try_mapping))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let piece_locks = (0..PIECE_LOCKS).map(|_| RwLock::new(())).collect();
+        Ok(Self{ files, maps, piece_locks })
+    }
+
+    fn piece_lock(&self, piece: &PieceInfo) -> &RwLock<()> {
+        &self.piece_locks[piece.index as usize % PIECE_LOCKS]
+    }
+
+    fn read_at(&self, pos: u64, buffer: &mut [u8]) -> anyhow::Result<()> {
+        for segment in file_segments(&self.files, pos, buffer.len() as u64) {
+            self.maps[segment.file_index].read(segment.file_pos, &mut buffer[segment.data_range])?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for MmapStorage {
+    async fn preallocate(&self) -> anyhow::Result<()> {
+        // the files are already resized when they are mapped
+        Ok(())
+    }
+
+    async fn read_block(&self, piece: &PieceInfo, begin: u32, length: u32) -> anyhow::Result<Vec<u8>> {
+        let Some(end) = begin.checked_add(length).filter(|end| *end <= piece.length) else {
+            bail!("block at {begin} with length {length} is out of range of piece {}", piece.index);
+        };
+        let mut block = vec![0; (end - begin) as usize];
+        let _piece = self.piece_lock(piece).read().expect("poisoned lock");
+        self.read_at(piece.file_start_pos as u64 + begin as u64, &mut block)?;
+        Ok(block)
+    }
+
+    async fn write_piece(&self, piece: &PieceInfo, data: &[u8]) -> anyhow::Result<()> {
+        if data.len() != piece.length as usize {
+            bail!("piece {} has length {}, but got {} bytes of data", piece.index, piece.length, data.len());
+        }
+        let _piece = self.piece_lock(piece).write().expect("poisoned lock");
+        for segment in file_segments(&self.files, piece.file_start_pos as u64, piece.length as u64) {
+            self.maps[segment.file_index].write(segment.file_pos, &data[segment.data_range])?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> anyhow::Result<()> {
+        for map in &self.maps {
+            map.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Files are always present after the storage is opened, so the hash is always returned
+    async fn hash_piece(&self, piece: &PieceInfo) -> anyhow::Result<Option<[u8; HASH_RAW_LENGTH]>> {
+        let mut data = vec![0; piece.length as usize];
+        {
+            let _piece = self.piece_lock(piece).read().expect("poisoned lock");
+            self.read_at(piece.file_start_pos as u64, &mut data)?;
+        }
+        Ok(Some(HashPool::global().digest(data).await?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::seed::test::get_data;
+    use crate::storage::test::{check_storage, create_multi_file_info};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mmap_storage() -> anyhow::Result<()> {
+        let data = get_data(260);
+        let info = create_multi_file_info(&data);
+        let dir = tempfile::tempdir()?;
+        let storage = MmapStorage::open(info.get_files_info(dir.path()), PreallocationMode::Sparse)?;
+        assert!(storage.maps.iter().any(|map| matches!(map, FileMap::Mapped{ .. })), "files should be mapped");
+        check_storage(storage, &info, &data, false).await?;
+        assert_eq!(&data[..150], &std::fs::read(dir.path().join("a"))?[..]);
+        assert_eq!(&data[150..], &std::fs::read(dir.path().join("dir/c"))?[..]);
+
+        // the data should be kept when the storage is reopened
+        let storage = MmapStorage::open(info.get_files_info(dir.path()), PreallocationMode::Sparse)?;
+        let piece = info.get_piece_info(1)?;
+        assert_eq!(Some(piece.hash), storage.hash_piece(&piece).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unmapped_fallback() -> anyhow::Result<()> {
+        let data = get_data(260);
+        let info = create_multi_file_info(&data);
+        let dir = tempfile::tempdir()?;
+        let storage = MmapStorage::open_without_mapping(info.get_files_info(dir.path()))?;
+        check_storage(storage, &info, &data, false).await?;
+        assert_eq!(&data[150..], &std::fs::read(dir.path().join("dir/c"))?[..]);
+        Ok(())
+    }
+}
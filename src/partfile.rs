@@ -0,0 +1,120 @@
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use anyhow::Context;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard};
+
+/// Returns the hidden file next to the data, that keeps the data of the skipped files
+pub(crate) fn partfile_path(data_path: &Path) -> PathBuf {
+    let name = data_path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    data_path.with_file_name(format!(".{name}.parts"))
+}
+
+/// Parts of the pieces that belong to the skipped files. The data is kept at its position in the torrent,
+/// so the file is sparse, and only takes the space of the pieces that overlap both skipped and wanted files
+pub(crate) struct PartFile {
+    path: PathBuf,
+    /// opened on the first use. The flag is set if the file is opened for writing
+    file: Mutex<Option<(File, bool)>>,
+}
+impl PartFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self{ path, file: Mutex::new(None) }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns false if the data was not written to the part file
+    pub async fn read_at(&self, pos: u64, buffer: &mut [u8]) -> anyhow::Result<bool> {
+        let mut file = self.file.lock().await;
+        if file.is_none() {
+            match File::open(&self.path).await {
+                Ok(opened) => *file = Some((opened, false)),
+                Err(error) if error.kind() == ErrorKind::NotFound => return Ok(false),
+                Err(error) => return Err(error).context(format!("failed to open {}", self.path.display())),
+            }
+        }
+        let (file, _) = file.as_mut().expect("part file should be opened");
+        let file_length = file.metadata().await.context("failed to get part file metadata")?.len();
+        if file_length < pos + buffer.len() as u64 {
+            return Ok(false);
+        }
+        file.seek(SeekFrom::Start(pos)).await.context("failed to seek part file")?;
+        file.read_exact(buffer).await.context("failed to read part file")?;
+        Ok(true)
+    }
+
+    pub async fn write_at(&self, pos: u64, data: &[u8]) -> anyhow::Result<()> {
+        let mut file = self.lock_writable().await?;
+        file.seek(SeekFrom::Start(pos)).await.context("failed to seek part file")?;
+        file.write_all(data).await.context("failed to write part file")?;
+        Ok(())
+    }
+
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        if let Some((file, true)) = &mut *self.file.lock().await {
+            file.flush().await.context("failed to flush part file")?;
+            file.sync_data().await.context("failed to sync part file")?;
+        }
+        Ok(())
+    }
+
+    /// Creates the part file if it does not exist, so that it could be tracked by the resume file
+    pub async fn create(&self) -> anyhow::Result<()> {
+        let _file = self.lock_writable().await?;
+        Ok(())
+    }
+
+    pub async fn remove(&self) -> anyhow::Result<()> {
+        let mut file = self.file.lock().await;
+        *file = None;
+        match tokio::fs::remove_file(&self.path).await {
+            Ok(()) => Ok(()),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(error).context(format!("failed to remove {}", self.path.display())),
+        }
+    }
+
+    /// The file could be opened for reading only, it's reopened then
+    async fn lock_writable(&self) -> anyhow::Result<MappedMutexGuard<'_, File>> {
+        let mut file = self.file.lock().await;
+        if !matches!(&*file, Some((_, true))) {
+            let opened = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&self.path)
+                .await
+                .context(format!("failed to open {}", self.path.display()))?;
+            *file = Some((opened, true));
+        }
+        Ok(MutexGuard::map(file, |file| &mut file.as_mut().expect("part file should be opened").0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_part_file() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        assert_eq!(dir.path().join(".data.parts"), partfile_path(&dir.path().join("data")));
+
+        let part_file = PartFile::new(partfile_path(&dir.path().join("data")));
+        let mut buffer = [0; 4];
+        assert!(!part_file.read_at(100, &mut buffer).await?, "missing part file should have no data");
+        part_file.write_at(100, b"data").await?;
+        assert!(part_file.read_at(100, &mut buffer).await?);
+        assert_eq!(b"data", &buffer);
+        assert!(!part_file.read_at(102, &mut buffer).await?, "data after the end should be missing");
+
+        part_file.remove().await?;
+        assert!(!part_file.path().exists());
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use std::net::{SocketAddrV4};
+use std::net::{SocketAddr, SocketAddrV4};
 use std::{cmp, mem, slice};
 use std::future::Future;
 use std::time::Duration;
@@ -6,27 +6,47 @@ use anyhow::{bail, Context};
 use sha1::{Digest, Sha1};
 use tokio::net::TcpStream;
 use tokio::io::{AsyncWriteExt, AsyncReadExt};
+use tokio::sync::watch;
 use tokio::time::timeout;
+use crate::choker::SharedChoker;
+use crate::hasher::{HashPool, PieceHasher};
+use crate::seed::SeedData;
+use crate::storage::Storage;
 use crate::torrent::{HASH_RAW_LENGTH, PieceInfo};
 use crate::tracker::{MY_PEER_ID, PEER_ID_LEN};
 
 const PROTOCOL_HEADER: &str = "BitTorrent protocol";
-const PADDING: &[u8] = &[0; 8];
+const RESERVED_LENGTH: usize = 8;
+/// BEP 6, the fast extension is enabled when both peers set this bit
+const FAST_EXTENSION_BYTE: usize = 7;
+const FAST_EXTENSION_BIT: u8 = 0x04;
+/// size of the allowed fast set that we give to the peers
+const ALLOWED_FAST_COUNT: u32 = 10;
 const BLOCK_SIZE: u32 = 16 * 1024;
 const BLOCK_HEADER_LENGTH: usize = 8;
 const MAX_LENGTH: u32 = BLOCK_SIZE + (BLOCK_HEADER_LENGTH as u32);
+const BLOCK_REQUEST_LENGTH: usize = 12;
+/// larger requests are rejected, this is the limit most clients use
+pub(crate) const MAX_REQUEST_LENGTH: u32 = BLOCK_SIZE;
+const OPERATION_TIMEOUT: Duration = Duration::from_millis(1500);
+/// peers are expected to send at least a keep-alive every 2 minutes
+const IDLE_TIMEOUT: Duration = Duration::from_secs(150);
 
 #[repr(C)]
-struct HandshakeMessage {
+pub(crate) struct HandshakeMessage {
     length: u8,
     header: [u8; PROTOCOL_HEADER.len()],
-    padding: [u8; PADDING.len()],
-    info_hash: [u8; HASH_RAW_LENGTH],
+    reserved: [u8; RESERVED_LENGTH],
+    pub info_hash: [u8; HASH_RAW_LENGTH],
     peer_id: [u8; PEER_ID_LEN],
 }
+impl HandshakeMessage {
+    fn supports_fast_extension(&self) -> bool {
+        (self.reserved[FAST_EXTENSION_BYTE] & FAST_EXTENSION_BIT) > 0
+    }
+}
 
-#[allow(dead_code)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 enum MessageType {
     Choke = 0,
     Unchoke = 1,
@@ -34,9 +54,36 @@ enum MessageType {
     NotInterested = 3,
     Have = 4,
     PiecesBitfield = 5,
-    Block = 6,
+    Request = 6,
     Piece = 7,
     Cancel = 8,
+    SuggestPiece = 0x0D,
+    HaveAll = 0x0E,
+    HaveNone = 0x0F,
+    RejectRequest = 0x10,
+    AllowedFast = 0x11,
+}
+impl MessageType {
+    fn from_u8(msg_type: u8) -> Option<Self> {
+        let msg_type = match msg_type {
+            0 => Self::Choke,
+            1 => Self::Unchoke,
+            2 => Self::Interested,
+            3 => Self::NotInterested,
+            4 => Self::Have,
+            5 => Self::PiecesBitfield,
+            6 => Self::Request,
+            7 => Self::Piece,
+            8 => Self::Cancel,
+            0x0D => Self::SuggestPiece,
+            0x0E => Self::HaveAll,
+            0x0F => Self::HaveNone,
+            0x10 => Self::RejectRequest,
+            0x11 => Self::AllowedFast,
+            _ => return None,
+        };
+        Some(msg_type)
+    }
 }
 
 #[repr(C)]
@@ -55,52 +102,424 @@ impl BlockRequestRaw {
     }
 }
 
+/// Returned from `Peer::download_piece` when the peer refuses to give us the piece,
+/// it should be given back to the other peers
+#[derive(Debug)]
+pub(crate) struct PieceRejected {
+    pub index: u32,
+}
+impl std::fmt::Display for PieceRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "peer rejected the request for piece {}", self.index)
+    }
+}
+impl std::error::Error for PieceRejected {}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+impl BlockRequest {
+    fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() != BLOCK_REQUEST_LENGTH {
+            bail!("invalid block request length {}, expected {BLOCK_REQUEST_LENGTH}", data.len());
+        }
+        let request = Self {
+            index: u32::from_be_bytes(data[0..4].try_into().unwrap()),
+            begin: u32::from_be_bytes(data[4..8].try_into().unwrap()),
+            length: u32::from_be_bytes(data[8..12].try_into().unwrap()),
+        };
+        Ok(request)
+    }
+
+    fn to_bytes(&self) -> [u8; BLOCK_REQUEST_LENGTH] {
+        let request = BlockRequestRaw::new(self.index, self.begin, self.length);
+        unsafe { get_bytes_ref_of_struct(&request) }.try_into().unwrap()
+    }
+}
+
 pub(crate) struct Peer {
     tcp: TcpStream,
+    info_hash: [u8; HASH_RAW_LENGTH],
     pub peer_id: [u8; PEER_ID_LEN],
     pub has_pieces: Vec<u8>,
+    pieces_count: u32,
+    read_buffer: Vec<u8>,
+    /// count of received messages, not including keep-alives
+    messages_received: u64,
+    fast_extension: bool,
+    am_choking: bool,
+    peer_choking: bool,
+    peer_interested: bool,
+    /// pieces that the peer allows us to request while we are choked
+    allowed_fast: Vec<u32>,
+    suggested_pieces: Vec<u32>,
+    /// pieces that we allow the peer to request while it is choked
+    our_allowed_fast: Vec<u32>,
 }
 impl Peer {
-    async fn read_message(&mut self, expect_type: MessageType) -> anyhow::Result<Vec<u8>> {
-        read_message(&mut self.tcp, expect_type).await
+    fn new(tcp: TcpStream, handshake: &HandshakeMessage, pieces_count: u32) -> Self {
+        Self {
+            tcp,
+            info_hash: handshake.info_hash,
+            peer_id: handshake.peer_id,
+            has_pieces: vec![0; bitfield_length(pieces_count)],
+            pieces_count,
+            read_buffer: vec![],
+            messages_received: 0,
+            fast_extension: handshake.supports_fast_extension(),
+            am_choking: true,
+            peer_choking: true,
+            peer_interested: false,
+            allowed_fast: vec![],
+            suggested_pieces: vec![],
+            our_allowed_fast: vec![],
+        }
+    }
+
+    /// Reads a message of any type, waiting for up to `wait`. Returns None for keep-alive messages.
+    /// This is cancel safe, partially received messages are kept in the buffer
+    async fn read_next_message(&mut self, wait: Duration) -> anyhow::Result<Option<(MessageType, Vec<u8>)>> {
+        let read = async {
+            loop {
+                if let Some(mut message) = take_buffered_message(&mut self.read_buffer)? {
+                    if message.is_empty() {
+                        return Ok(None);
+                    }
+                    let data = message.split_off(1);
+                    let Some(msg_type) = MessageType::from_u8(message[0]) else {
+                        bail!("got message of unknown type {}", message[0]);
+                    };
+                    self.messages_received += 1;
+                    return Ok(Some((msg_type, data)));
+                }
+                let read_length = self.tcp.read_buf(&mut self.read_buffer).await.context("failed to read message")?;
+                if read_length == 0 {
+                    bail!("connection closed by the peer");
+                }
+            }
+        };
+        timeout(wait, read).await.context("timed out waiting for a message")?
+    }
+
+    /// Reads a message of any type, skipping keep-alive messages
+    async fn read_message(&mut self) -> anyhow::Result<(MessageType, Vec<u8>)> {
+        loop {
+            if let Some(message) = self.read_next_message(OPERATION_TIMEOUT).await? {
+                return Ok(message);
+            }
+        }
     }
     async fn write_message(&mut self, msg_type: MessageType, data: &[u8]) -> anyhow::Result<()> {
         write_message(&mut self.tcp, msg_type, data).await
     }
 
+    pub fn socket_string(&self) -> String {
+        match self.tcp.peer_addr() {
+            Ok(socket) => socket.to_string(),
+            Err(_) => "unknown peer".to_string(),
+        }
+    }
+
     pub fn has_piece(&self, piece_index: u32) -> bool {
         piece_exists(piece_index, &self.has_pieces)
     }
 
+    pub fn suggested_pieces(&self) -> &[u32] {
+        &self.suggested_pieces
+    }
+
+    pub fn has_any_piece(&self) -> bool {
+        self.has_pieces.iter().any(|x| *x != 0)
+    }
+
+    /// Lets the peer know which pieces we already have, and waits until it unchokes us
+    pub async fn start_download(&mut self, bitfield: &[u8]) -> anyhow::Result<()> {
+        // our pieces have to be announced right after the handshake
+        self.send_bitfield(bitfield).await?;
+        // the bitfield is optional, peers that have nothing yet are kept until they announce some pieces
+        if !self.has_any_piece() {
+            self.wait_for_new_pieces().await?;
+        }
+        self.write_message(MessageType::Interested, &[]).await?;
+        while self.peer_choking {
+            let (msg_type, data) = self.read_message().await.context("waiting for unchoke")?;
+            if !self.handle_state_message(msg_type, &data)? && !self.handle_ignored_download_message(msg_type) {
+                bail!("got message of type {msg_type:?} instead of unchoke");
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits until the peer announces new pieces with a have or a bitfield message
+    pub async fn wait_for_new_pieces(&mut self) -> anyhow::Result<()> {
+        loop {
+            let Some((msg_type, data)) = self.read_next_message(IDLE_TIMEOUT).await? else {
+                continue; // keep-alive
+            };
+            if self.handle_state_message(msg_type, &data)? {
+                if matches!(msg_type, MessageType::Have | MessageType::PiecesBitfield | MessageType::HaveAll) {
+                    return Ok(());
+                }
+                continue;
+            }
+            if !self.handle_ignored_download_message(msg_type) {
+                bail!("unexpected message {msg_type:?} while waiting for pieces");
+            }
+        }
+    }
+
+    /// Messages from the peer that may arrive while we are downloading, but do not affect the download.
+    /// Returns false if the message was not handled
+    fn handle_ignored_download_message(&mut self, msg_type: MessageType) -> bool {
+        match msg_type {
+            MessageType::Interested => self.peer_interested = true,
+            MessageType::NotInterested => self.peer_interested = false,
+            // we keep the downloading peers choked, requests from choked peers are discarded
+            MessageType::Request => {},
+            _ => return false,
+        }
+        true
+    }
+
+    /// Lets the peer know which pieces we have, and which of them it can download while choked
+    pub async fn start_upload(&mut self, bitfield: &[u8]) -> anyhow::Result<()> {
+        self.send_bitfield(bitfield).await?;
+        if !self.fast_extension {
+            return Ok(());
+        }
+        let Ok(SocketAddr::V4(socket)) = self.tcp.peer_addr() else {
+            // the allowed fast set is only defined for ipv4
+            return Ok(());
+        };
+        let allowed_fast = allowed_fast_set(socket.ip().octets(), &self.info_hash, self.pieces_count, ALLOWED_FAST_COUNT);
+        for piece_index in allowed_fast {
+            if piece_exists(piece_index, bitfield) {
+                self.write_message(MessageType::AllowedFast, &piece_index.to_be_bytes()).await?;
+                self.our_allowed_fast.push(piece_index);
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_bitfield(&mut self, bitfield: &[u8]) -> anyhow::Result<()> {
+        if self.fast_extension {
+            let have_count = (0..self.pieces_count).filter(|index| piece_exists(*index, bitfield)).count();
+            if have_count == 0 {
+                return self.write_message(MessageType::HaveNone, &[]).await;
+            }
+            if have_count == self.pieces_count as usize {
+                return self.write_message(MessageType::HaveAll, &[]).await;
+            }
+        }
+        // the bitfield message is optional, and peers with no pieces are allowed to skip it
+        if bitfield.iter().all(|x| *x == 0) {
+            return Ok(());
+        }
+        self.write_message(MessageType::PiecesBitfield, bitfield).await
+    }
+
+    pub async fn send_have(&mut self, piece_index: u32) -> anyhow::Result<()> {
+        self.write_message(MessageType::Have, &piece_index.to_be_bytes()).await
+    }
+
+    fn set_bitfield(&mut self, bitfield: &[u8]) -> anyhow::Result<()> {
+        validate_bitfield(bitfield, self.pieces_count)?;
+        self.has_pieces = bitfield.to_vec();
+        Ok(())
+    }
+
+    fn parse_piece_index(&self, msg_type: MessageType, data: &[u8]) -> anyhow::Result<u32> {
+        let Ok(piece_index) = data.try_into() else {
+            bail!("invalid {msg_type:?} message length {}", data.len());
+        };
+        let piece_index = u32::from_be_bytes(piece_index);
+        if piece_index >= self.pieces_count {
+            bail!("got {msg_type:?} for invalid piece {piece_index}, torrent only has {}", self.pieces_count);
+        }
+        Ok(piece_index)
+    }
+
+    /// Updates the state of the connection for messages that do not require a response from us.
+    /// Returns false if the message was not handled.
+    fn handle_state_message(&mut self, msg_type: MessageType, data: &[u8]) -> anyhow::Result<bool> {
+        let is_fast_message = matches!(
+            msg_type,
+            MessageType::SuggestPiece | MessageType::HaveAll | MessageType::HaveNone | MessageType::RejectRequest | MessageType::AllowedFast
+        );
+        if is_fast_message && !self.fast_extension {
+            bail!("got {msg_type:?}, but the fast extension was not negotiated");
+        }
+        let is_bitfield_message = matches!(msg_type, MessageType::PiecesBitfield | MessageType::HaveAll | MessageType::HaveNone);
+        if is_bitfield_message && self.messages_received != 1 {
+            bail!("got {msg_type:?}, but it is only allowed right after the handshake");
+        }
+        match msg_type {
+            MessageType::Choke => self.peer_choking = true,
+            MessageType::Unchoke => self.peer_choking = false,
+            MessageType::Have => {
+                let piece_index = self.parse_piece_index(msg_type, data)?;
+                set_piece(piece_index, &mut self.has_pieces);
+            },
+            MessageType::PiecesBitfield => self.set_bitfield(data)?,
+            MessageType::HaveAll => {
+                self.has_pieces = vec![0; bitfield_length(self.pieces_count)];
+                for piece_index in 0..self.pieces_count {
+                    set_piece(piece_index, &mut self.has_pieces);
+                }
+            },
+            MessageType::HaveNone => self.has_pieces = vec![0; bitfield_length(self.pieces_count)],
+            MessageType::SuggestPiece => {
+                let piece_index = self.parse_piece_index(msg_type, data)?;
+                if !self.suggested_pieces.contains(&piece_index) {
+                    self.suggested_pieces.push(piece_index);
+                }
+            },
+            MessageType::AllowedFast => {
+                let piece_index = self.parse_piece_index(msg_type, data)?;
+                if !self.allowed_fast.contains(&piece_index) {
+                    self.allowed_fast.push(piece_index);
+                }
+            },
+            // we do not queue the uploads, every request is served as soon as it is received
+            MessageType::Cancel => {},
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Serves block requests from the local data until the peer disconnects.
+    pub async fn serve(&mut self, seed: &SeedData<impl Storage>, choker: &SharedChoker) -> anyhow::Result<()> {
+        let socket = self.tcp.peer_addr().context("failed to get peer address")?;
+        let mut choke_changed = choker.add_peer(socket);
+        let result = self.serve_messages(seed, choker, socket, &mut choke_changed).await;
+        choker.remove_peer(&socket);
+        result
+    }
+
+    async fn serve_messages(&mut self, seed: &SeedData<impl Storage>, choker: &SharedChoker, socket: SocketAddr, choke_changed: &mut watch::Receiver<()>) -> anyhow::Result<()> {
+        loop {
+            tokio::select! {
+                message = self.read_next_message(IDLE_TIMEOUT) => {
+                    let Some((msg_type, data)) = message? else {
+                        continue; // keep-alive
+                    };
+                    self.handle_upload_message(msg_type, &data, seed, choker, socket).await?;
+                },
+                changed = choke_changed.changed() => {
+                    changed.context("choker has stopped")?;
+                    self.update_choke(choker.is_unchoked(&socket)).await?;
+                },
+            }
+        }
+    }
+
+    async fn handle_upload_message(&mut self, msg_type: MessageType, data: &[u8], seed: &SeedData<impl Storage>, choker: &SharedChoker, socket: SocketAddr) -> anyhow::Result<()> {
+        if self.handle_state_message(msg_type, data)? {
+            return Ok(());
+        }
+        match msg_type {
+            MessageType::Interested | MessageType::NotInterested => {
+                self.peer_interested = msg_type == MessageType::Interested;
+                choker.set_interested(&socket, self.peer_interested);
+                self.update_choke(choker.is_unchoked(&socket)).await?;
+            },
+            MessageType::Request => {
+                let request = BlockRequest::parse(data)?;
+                let allowed = !self.am_choking || self.our_allowed_fast.contains(&request.index);
+                let validation = seed.validate_request(&request);
+                if self.fast_extension && (!allowed || validation.is_err()) {
+                    // with the fast extension every request has to be answered
+                    return self.write_message(MessageType::RejectRequest, &request.to_bytes()).await;
+                }
+                validation?;
+                if !allowed {
+                    // requests from choked peers are discarded
+                    return Ok(());
+                }
+                let block = seed.read_block(&request).await?;
+                let mut response = Vec::with_capacity(BLOCK_HEADER_LENGTH + block.len());
+                response.extend_from_slice(&request.index.to_be_bytes());
+                response.extend_from_slice(&request.begin.to_be_bytes());
+                response.extend_from_slice(&block);
+                self.write_message(MessageType::Piece, &response).await?;
+                choker.record_uploaded(&socket, block.len() as u64);
+            },
+            _ => bail!("unexpected message {msg_type:?} from a downloading peer"),
+        }
+        Ok(())
+    }
+
+    async fn update_choke(&mut self, unchoked: bool) -> anyhow::Result<()> {
+        if unchoked != self.am_choking {
+            // already in the requested state
+            return Ok(());
+        }
+        self.am_choking = !unchoked;
+        let msg_type = if unchoked { MessageType::Unchoke } else { MessageType::Choke };
+        self.write_message(msg_type, &[]).await
+    }
+
     pub async fn download_piece(&mut self, piece_info: PieceInfo) -> anyhow::Result<Vec<u8>> {
         let PieceInfo{ index: piece_index, length: piece_size, hash: piece_hash, .. } = piece_info;
 
         if !self.has_piece(piece_index) {
             bail!("peer does not have piece {piece_index}");
         }
+        if self.peer_choking && !self.allowed_fast.contains(&piece_index) {
+            return Err(PieceRejected{ index: piece_index }.into());
+        }
+        self.suggested_pieces.retain(|index| *index != piece_index);
 
         let mut full_piece = Vec::with_capacity(piece_size as usize);
+        // blocks are hashed on the hashing pool while the next ones are downloaded
+        let mut hasher = PieceHasher::new(HashPool::global());
         let mut block_no = 0;
         while let Some((block_start, block_length)) = Self::next_block_params(block_no, piece_size) {
             block_no += 1;
-            let block_request = BlockRequestRaw::new(piece_index, block_start, block_length);
-            self.write_message(MessageType::Block, unsafe { get_bytes_ref_of_struct(&block_request) }).await?;
+            let block_request = BlockRequest{ index: piece_index, begin: block_start, length: block_length };
+            self.write_message(MessageType::Request, &block_request.to_bytes()).await?;
 
-            let block_response = self.read_message(MessageType::Piece).await?;
+            let block_response = self.read_block_response(&block_request).await?;
             let block = Self::extract_block_from_response(&block_response, piece_index, block_no, block_start, block_length)?;
             full_piece.extend_from_slice(block);
+            hasher.update(block_start as u64, block.to_vec()).await?;
         }
 
-        let mut hasher = Sha1::new();
-        hasher.update(&full_piece);
-        let actual_hash = hasher.finalize();
-        let actual_hash: [u8; HASH_RAW_LENGTH] = actual_hash.try_into().context("failed to calc hash")?;
+        let actual_hash = hasher.finish(piece_size as u64).await?;
         if actual_hash != piece_hash {
             bail!("hash does not match, expected {}, actual {}", hex::encode(piece_hash), hex::encode(actual_hash));
         }
         Ok(full_piece)
     }
 
+    async fn read_block_response(&mut self, block_request: &BlockRequest) -> anyhow::Result<Vec<u8>> {
+        loop {
+            let (msg_type, data) = self.read_message().await?;
+            if self.handle_state_message(msg_type, &data)? {
+                // with the fast extension the pending request is either served or rejected after the choke
+                if self.peer_choking && !self.fast_extension {
+                    bail!("peer choked us while downloading");
+                }
+                continue;
+            }
+            if self.handle_ignored_download_message(msg_type) {
+                continue;
+            }
+            match msg_type {
+                MessageType::Piece => return Ok(data),
+                MessageType::RejectRequest => {
+                    if BlockRequest::parse(&data)? == *block_request {
+                        return Err(PieceRejected{ index: block_request.index }.into());
+                    }
+                },
+                _ => bail!("unexpected message {msg_type:?} while waiting for a block"),
+            }
+        }
+    }
+
     fn next_block_params(block_no: u32, piece_size: u32) -> Option<(u32, u32)> {
         let block_start = block_no * BLOCK_SIZE;
         if block_start >= piece_size {
@@ -130,38 +549,102 @@ impl Peer {
     }
 }
 
-pub(crate) async fn init_peer(info_hash: [u8; 20], socket: &SocketAddrV4) -> anyhow::Result<Peer> {
-    let mut tcp = TcpStream::connect(socket).await.context("failed to connect")?;
-    let peer_id = handshake(&mut tcp, &info_hash).await?;
-    let has_pieces = read_message(&mut tcp, MessageType::PiecesBitfield).await?;
-    if has_pieces.iter().all(|x| *x == 0) {
-        bail!("peer has no pieces");
-    }
-    write_message(&mut tcp, MessageType::Interested, &[]).await?;
-    let _ = read_message(&mut tcp, MessageType::Unchoke).await?;
-    let peer = Peer{ tcp, peer_id, has_pieces };
+pub(crate) async fn connect_peer(info_hash: [u8; 20], pieces_count: u32, socket: &SocketAddrV4) -> anyhow::Result<Peer> {
+    let mut tcp = do_with_timeout(async {
+        TcpStream::connect(socket).await.context("failed to connect")
+    }).await?;
+    let handshake_message = handshake(&mut tcp, &info_hash).await?;
+    Ok(Peer::new(tcp, &handshake_message, pieces_count))
+}
+
+pub(crate) async fn init_peer(info_hash: [u8; 20], pieces_count: u32, socket: &SocketAddrV4, bitfield: &[u8]) -> anyhow::Result<Peer> {
+    let mut peer = connect_peer(info_hash, pieces_count, socket).await?;
+    peer.start_download(bitfield).await?;
     Ok(peer)
 }
 
-async fn handshake(tcp: &mut TcpStream, info_hash: &[u8; 20]) -> anyhow::Result<[u8; PEER_ID_LEN]> {
-    let handshake = async {
-        let mut handshake_message = HandshakeMessage {
+async fn handshake(tcp: &mut TcpStream, info_hash: &[u8; 20]) -> anyhow::Result<HandshakeMessage> {
+    send_handshake(tcp, info_hash).await?;
+    let handshake_message = read_handshake(tcp).await?;
+    if handshake_message.info_hash != *info_hash {
+        bail!("received invalid hash hex {} expected {}", hex::encode(handshake_message.info_hash), hex::encode(info_hash));
+    }
+    Ok(handshake_message)
+}
+
+/// Reads the handshake of a peer that has connected to us.
+/// The info hash should be checked before calling `accept_peer`
+pub(crate) async fn receive_handshake(tcp: &mut TcpStream) -> anyhow::Result<HandshakeMessage> {
+    read_handshake(tcp).await
+}
+
+/// Finishes the handshake with a peer that has connected to us
+pub(crate) async fn accept_peer(mut tcp: TcpStream, handshake_message: HandshakeMessage, pieces_count: u32) -> anyhow::Result<Peer> {
+    send_handshake(&mut tcp, &handshake_message.info_hash).await?;
+    Ok(Peer::new(tcp, &handshake_message, pieces_count))
+}
+
+async fn send_handshake(tcp: &mut TcpStream, info_hash: &[u8; 20]) -> anyhow::Result<()> {
+    let send = async {
+        let handshake_message = HandshakeMessage {
             length: PROTOCOL_HEADER.len() as u8,
             header: PROTOCOL_HEADER.as_bytes().try_into().unwrap(),
-            padding: PADDING.try_into().unwrap(),
-            info_hash: info_hash.clone(),
+            reserved: our_reserved_bytes(),
+            info_hash: *info_hash,
             peer_id: MY_PEER_ID.as_bytes().try_into().unwrap(),
         };
-        let handshake_bytes = unsafe { get_bytes_ref_of_struct_mut(&mut handshake_message) };
+        let handshake_bytes = unsafe { get_bytes_ref_of_struct(&handshake_message) };
         tcp.write_all(handshake_bytes).await.context("failed to send handshake")?;
         tcp.flush().await.context("failed to flush handshake")?;
+        Ok(())
+    };
+    do_with_timeout(send).await
+}
+
+async fn read_handshake(tcp: &mut TcpStream) -> anyhow::Result<HandshakeMessage> {
+    let read = async {
+        let mut handshake_message = HandshakeMessage {
+            length: 0,
+            header: [0; PROTOCOL_HEADER.len()],
+            reserved: [0; RESERVED_LENGTH],
+            info_hash: [0; HASH_RAW_LENGTH],
+            peer_id: [0; PEER_ID_LEN],
+        };
+        let handshake_bytes = unsafe { get_bytes_ref_of_struct_mut(&mut handshake_message) };
         tcp.read_exact(handshake_bytes).await.context("failed to read handshake")?;
         Ok(handshake_message)
     };
-    let handshake_message = do_with_timeout(handshake).await?;
-    validate_handshake(info_hash, &handshake_message)?;
-    let peer_id = handshake_message.peer_id;
-    Ok(peer_id)
+    let handshake_message = do_with_timeout(read).await?;
+    validate_handshake(&handshake_message)?;
+    Ok(handshake_message)
+}
+
+fn our_reserved_bytes() -> [u8; RESERVED_LENGTH] {
+    let mut reserved = [0; RESERVED_LENGTH];
+    reserved[FAST_EXTENSION_BYTE] |= FAST_EXTENSION_BIT;
+    reserved
+}
+
+/// Canonical allowed fast set from BEP 6, it depends only on the ip of the peer, so it can not be gamed by reconnecting
+fn allowed_fast_set(ip: [u8; 4], info_hash: &[u8; HASH_RAW_LENGTH], pieces_count: u32, count: u32) -> Vec<u32> {
+    let count = cmp::min(count, pieces_count) as usize;
+    let mut allowed = Vec::with_capacity(count);
+    let mut hash = Vec::with_capacity(4 + HASH_RAW_LENGTH);
+    hash.extend_from_slice(&[ip[0], ip[1], ip[2], 0]);
+    hash.extend_from_slice(info_hash);
+    while allowed.len() < count {
+        hash = Sha1::digest(&hash).to_vec();
+        for chunk in hash.chunks(4) {
+            if allowed.len() >= count {
+                break;
+            }
+            let index = u32::from_be_bytes(chunk.try_into().unwrap()) % pieces_count;
+            if !allowed.contains(&index) {
+                allowed.push(index);
+            }
+        }
+    }
+    allowed
 }
 
 unsafe fn get_bytes_ref_of_struct_mut<T: Sized>(struct_ref: &mut T) -> &mut [u8] {
@@ -178,49 +661,39 @@ unsafe fn get_bytes_ref_of_struct<T: Sized>(struct_ref: &T) -> &[u8] {
 }
 
 async fn do_with_timeout<T: Sized>(future: impl Future<Output = anyhow::Result<T>> + Sized) -> anyhow::Result<T> {
-    let action = timeout(Duration::from_millis(1500), future);
+    let action = timeout(OPERATION_TIMEOUT, future);
     let result = action.await.context("operation timed out")?;
     result
 }
 
-fn validate_handshake(info_hash: &[u8; 20], handshake_message: &HandshakeMessage) -> anyhow::Result<()> {
+fn validate_handshake(handshake_message: &HandshakeMessage) -> anyhow::Result<()> {
     if handshake_message.length != (PROTOCOL_HEADER.len() as u8) {
         bail!("received invalid header length {}", handshake_message.length);
     }
     if handshake_message.header != PROTOCOL_HEADER.as_bytes() {
         bail!("received invalid header {:?}", std::str::from_utf8(&handshake_message.header));
     }
-    if handshake_message.info_hash != *info_hash {
-        bail!("received invalid hash hex {} expected {}", hex::encode(handshake_message.info_hash), hex::encode(info_hash));
-    }
     Ok(())
 }
 
-async fn read_message(tcp: &mut TcpStream, expect_type: MessageType) -> anyhow::Result<Vec<u8>> {
-    let read = async {
-        let mut message_length_bytes = [0u8; 4];
-        tcp.read_exact(&mut message_length_bytes).await.context(format!("failed to read message length for {expect_type:?}"))?;
-        let message_length = u32::from_be_bytes(message_length_bytes);
-        assert!(message_length > 0, "got a heartbeat message, not prepared for that");
-        let data_length = message_length - 1;
-        if data_length > MAX_LENGTH {
-            bail!("received too large message length {data_length} {message_length_bytes:?}");
-        }
-
-        let mut msg_type = [0u8];
-        tcp.read_exact(&mut msg_type).await.context("failed to read message type")?;
-        let msg_type = msg_type[0];
-        if msg_type != (expect_type as u8) {
-            bail!("got message of type {msg_type} instead of expected {expect_type:?}");
-        }
-
-        let mut data = vec![0u8; data_length as usize];
-        if data_length > 0 {
-            tcp.read_exact(&mut data).await.context("failed to read message data")?;
-        }
-        Ok(data)
+/// Takes the first message from the buffer if it was fully received.
+/// Returns the message type and data, an empty message is a keep-alive
+fn take_buffered_message(buffer: &mut Vec<u8>) -> anyhow::Result<Option<Vec<u8>>> {
+    let Some(message_length_bytes) = buffer.get(..4) else {
+        return Ok(None);
     };
-    do_with_timeout(read).await.context(format!("reading message {expect_type:?}"))
+    let message_length_bytes: [u8; 4] = message_length_bytes.try_into().unwrap();
+    let message_length = u32::from_be_bytes(message_length_bytes);
+    if message_length > MAX_LENGTH + 1 {
+        bail!("received too large message length {message_length} {message_length_bytes:?}");
+    }
+    let full_length = 4 + message_length as usize;
+    if buffer.len() < full_length {
+        return Ok(None);
+    }
+    let message = buffer[4..full_length].to_vec();
+    buffer.drain(..full_length);
+    Ok(Some(message))
 }
 
 async fn write_message(tcp: &mut TcpStream, msg_type: MessageType, data: &[u8]) -> anyhow::Result<()> {
@@ -229,7 +702,7 @@ async fn write_message(tcp: &mut TcpStream, msg_type: MessageType, data: &[u8])
         tcp.write_all(&length.to_be_bytes()).await.context("failed to write message length")?;
         tcp.write_all(&[msg_type as u8]).await.context("failed to write message type")?;
         if length > 0 {
-            tcp.write_all(&data).await.context("failed to write message data")?;
+            tcp.write_all(data).await.context("failed to write message data")?;
         }
         tcp.flush().await.context("failed to flush message")?;
         Ok(())
@@ -237,7 +710,7 @@ async fn write_message(tcp: &mut TcpStream, msg_type: MessageType, data: &[u8])
     do_with_timeout(write).await
 }
 
-fn piece_exists(piece_index: u32, pieces_bitmap: &[u8]) -> bool {
+pub(crate) fn piece_exists(piece_index: u32, pieces_bitmap: &[u8]) -> bool {
     // extracted to a separate function for easy testing. The struct requires a TcpStream
     let byte_key = (piece_index / 8) as usize;
     let bit_no = piece_index % 8;
@@ -246,14 +719,222 @@ fn piece_exists(piece_index: u32, pieces_bitmap: &[u8]) -> bool {
     };
     let bit_no = 7 - bit_no;
     let bitmask = 1u8 << bit_no;
-    let has_bit = (*bitmap_byte & bitmask) > 0;
-    has_bit
+    (*bitmap_byte & bitmask) > 0
+}
+
+pub(crate) fn set_piece(piece_index: u32, pieces_bitmap: &mut [u8]) {
+    let byte_key = (piece_index / 8) as usize;
+    let bit_no = 7 - (piece_index % 8);
+    pieces_bitmap[byte_key] |= 1u8 << bit_no;
+}
+
+/// The bitfield should have exactly one bit per piece, with the spare bits at the end set to zero
+pub(crate) fn validate_bitfield(bitfield: &[u8], pieces_count: u32) -> anyhow::Result<()> {
+    let expected_length = bitfield_length(pieces_count);
+    if bitfield.len() != expected_length {
+        bail!("invalid bitfield length {}, expected {expected_length}", bitfield.len());
+    }
+    let spare_bits = (expected_length * 8) as u32 - pieces_count;
+    let spare_mask = ((1u16 << spare_bits) - 1) as u8;
+    if bitfield.last().is_some_and(|last| (last & spare_mask) != 0) {
+        bail!("bitfield has spare bits set");
+    }
+    Ok(())
+}
+
+pub(crate) fn bitfield_length(pieces_count: u32) -> usize {
+    pieces_count.div_ceil(8) as usize
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
+    use tokio::net::TcpListener;
+    use crate::choker::ChokerMode;
+    use crate::seed::test::{create_seed, get_data, PIECE_LENGTH};
+    use crate::storage::MemoryStorage;
     use super::*;
 
+    pub(crate) async fn local_listener() -> anyhow::Result<(TcpListener, SocketAddrV4)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let std::net::SocketAddr::V4(socket) = listener.local_addr()? else {
+            unreachable!("listener is bound to an ipv4 address");
+        };
+        Ok((listener, socket))
+    }
+
+    /// Serves the seed to the first peer that connects
+    pub(crate) async fn spawn_seeder(seed: SeedData<MemoryStorage>) -> anyhow::Result<SocketAddrV4> {
+        let (listener, socket) = local_listener().await?;
+        tokio::spawn(async move {
+            let (mut tcp, _) = listener.accept().await?;
+            let handshake_message = receive_handshake(&mut tcp).await?;
+            let mut peer = accept_peer(tcp, handshake_message, seed.pieces_count()).await?;
+            peer.start_upload(&seed.bitfield).await?;
+            peer.serve(&seed, &SharedChoker::new(ChokerMode::Seeding)).await
+        });
+        Ok(socket)
+    }
+
+    /// Accepts the first peer that connects, and then only sends keep-alives to it
+    pub(crate) async fn spawn_idle_peer(pieces_count: u32) -> anyhow::Result<SocketAddrV4> {
+        let (listener, socket) = local_listener().await?;
+        tokio::spawn(async move {
+            let (mut tcp, _) = listener.accept().await?;
+            let handshake_message = receive_handshake(&mut tcp).await?;
+            let mut peer = accept_peer(tcp, handshake_message, pieces_count).await?;
+            while peer.tcp.write_all(&[0; 4]).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            anyhow::Ok(())
+        });
+        Ok(socket)
+    }
+
+    #[tokio::test]
+    async fn test_serve() -> anyhow::Result<()> {
+        let data = get_data(PIECE_LENGTH as usize * 2 + 100);
+        let seed = create_seed(&data).await?;
+        let info_hash = [1; HASH_RAW_LENGTH];
+        let pieces_count = seed.pieces_count();
+        let (listener, socket) = local_listener().await?;
+
+        let seeder = tokio::spawn(async move {
+            let mut peer = connect_peer(info_hash, pieces_count, &socket).await?;
+            peer.start_upload(&seed.bitfield).await?;
+            peer.serve(&seed, &SharedChoker::new(ChokerMode::Seeding)).await
+        });
+
+        let (mut tcp, _) = listener.accept().await?;
+        let handshake_message = handshake(&mut tcp, &info_hash).await?;
+        let mut peer = Peer::new(tcp, &handshake_message, pieces_count);
+        assert!(peer.fast_extension, "fast extension should be negotiated");
+        peer.start_download(&vec![0; bitfield_length(pieces_count)]).await?;
+        assert!((0..pieces_count).all(|index| peer.has_piece(index)), "seeder should send have all");
+        for (index, piece) in data.chunks(PIECE_LENGTH as usize).enumerate() {
+            let piece_info = PieceInfo {
+                index: index as u32,
+                length: piece.len() as u32,
+                hash: Sha1::digest(piece).into(),
+                file_start_pos: index as u32 * PIECE_LENGTH,
+            };
+            let piece_data = peer.download_piece(piece_info).await?;
+            assert_eq!(piece, piece_data);
+        }
+
+        let request = BlockRequest{ index: 0, begin: 0, length: MAX_REQUEST_LENGTH + 1 };
+        peer.write_message(MessageType::Request, &request.to_bytes()).await?;
+        let (msg_type, data) = peer.read_message().await?;
+        assert_eq!(MessageType::RejectRequest, msg_type, "oversized request should be rejected");
+        assert_eq!(request, BlockRequest::parse(&data)?);
+
+        drop(peer);
+        let result = seeder.await?;
+        assert!(result.is_err(), "seeder should stop when the peer disconnects");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rejected_piece() -> anyhow::Result<()> {
+        let info_hash = [1; HASH_RAW_LENGTH];
+        let (listener, socket) = local_listener().await?;
+        let seeder = tokio::spawn(async move {
+            let (mut tcp, _) = listener.accept().await?;
+            let handshake_message = handshake(&mut tcp, &info_hash).await?;
+            let mut peer = Peer::new(tcp, &handshake_message, 1);
+            peer.write_message(MessageType::HaveAll, &[]).await?;
+            let (msg_type, _) = peer.read_message().await?;
+            assert_eq!(MessageType::HaveNone, msg_type, "downloader should announce that it has no pieces");
+            let (msg_type, _) = peer.read_message().await?;
+            assert_eq!(MessageType::Interested, msg_type);
+            peer.write_message(MessageType::Unchoke, &[]).await?;
+            let (msg_type, data) = peer.read_message().await?;
+            assert_eq!(MessageType::Request, msg_type);
+            peer.write_message(MessageType::RejectRequest, &data).await?;
+            anyhow::Ok(peer)
+        });
+
+        let mut peer = init_peer(info_hash, 1, &socket, &[0]).await?;
+        let piece_info = PieceInfo{ index: 0, length: 100, hash: [0; HASH_RAW_LENGTH], file_start_pos: 0 };
+        let error = peer.download_piece(piece_info).await.expect_err("piece should be rejected");
+        let rejected = error.downcast_ref::<PieceRejected>().expect("error should be a rejection");
+        assert_eq!(0, rejected.index);
+        let _seeder_peer = seeder.await??;
+        Ok(())
+    }
+
+    #[test]
+    fn test_allowed_fast_set() {
+        // example from BEP 6
+        let ip = [80, 4, 4, 200];
+        let info_hash = [0xaa; HASH_RAW_LENGTH];
+        assert_eq!(vec![1059, 431, 808, 1217, 287, 376, 1188], allowed_fast_set(ip, &info_hash, 1313, 7));
+        assert_eq!(vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508], allowed_fast_set(ip, &info_hash, 1313, 9));
+        assert_eq!(vec![0], allowed_fast_set(ip, &info_hash, 1, 9), "set can not be larger than the torrent");
+    }
+
+    #[test]
+    fn test_parse_block_request() {
+        let request = BlockRequest{ index: 1, begin: 2, length: 3 };
+        assert_eq!(request, BlockRequest::parse(&request.to_bytes()).expect("request should be valid"));
+        assert!(BlockRequest::parse(&[0; 11]).is_err(), "short request should be rejected");
+    }
+
+    #[tokio::test]
+    async fn test_lazy_bitfield() -> anyhow::Result<()> {
+        let info_hash = [1; HASH_RAW_LENGTH];
+        let (listener, socket) = local_listener().await?;
+        let seeder = tokio::spawn(async move {
+            let (mut tcp, _) = listener.accept().await?;
+            let handshake_message = handshake(&mut tcp, &info_hash).await?;
+            let mut peer = Peer::new(tcp, &handshake_message, 10);
+            // no bitfield, the pieces are announced later
+            peer.write_message(MessageType::Unchoke, &[]).await?;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            peer.send_have(3).await?;
+            peer.send_have(9).await?;
+            let (msg_type, data) = peer.read_message().await?;
+            assert_eq!((MessageType::PiecesBitfield, vec![0b01000000, 0]), (msg_type, data), "downloader should announce its pieces");
+            let (msg_type, _) = peer.read_message().await?;
+            assert_eq!(MessageType::Interested, msg_type);
+            peer.write_message(MessageType::PiecesBitfield, &[0, 0]).await?;
+            let result = peer.read_message().await;
+            assert!(result.is_err(), "connection should be dropped on a late bitfield");
+            anyhow::Ok(())
+        });
+
+        let mut peer = init_peer(info_hash, 10, &socket, &[0b01000000, 0]).await?;
+        assert!(peer.has_piece(3));
+        assert!(!peer.has_piece(4));
+        peer.wait_for_new_pieces().await?;
+        assert!(peer.has_piece(9));
+        let result = peer.wait_for_new_pieces().await;
+        assert!(result.is_err(), "bitfield after the other messages should be rejected");
+        drop(peer);
+        seeder.await??;
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_bitfield() {
+        assert!(validate_bitfield(&[0b11111111], 8).is_ok());
+        assert!(validate_bitfield(&[0b11111111, 0b11000000], 10).is_ok());
+        assert!(validate_bitfield(&[], 0).is_ok());
+        assert!(validate_bitfield(&[0b11111111], 10).is_err(), "bitfield is too short");
+        assert!(validate_bitfield(&[0b11111111, 0, 0], 10).is_err(), "bitfield is too long");
+        assert!(validate_bitfield(&[0b11111111, 0b11100000], 10).is_err(), "spare bit is set");
+        assert!(validate_bitfield(&[0, 0b00000001], 15).is_err(), "last spare bit is set");
+    }
+
+    #[test]
+    fn test_set_piece() {
+        let mut pieces = vec![0; bitfield_length(12)];
+        assert_eq!(2, pieces.len());
+        set_piece(0, &mut pieces);
+        set_piece(3, &mut pieces);
+        set_piece(11, &mut pieces);
+        assert_eq!(vec![0b10010000, 0b00010000], pieces);
+    }
+
     #[test]
     fn test_next_block_params() {
         let params = Peer::next_block_params(0, 300).expect("block 0 should exist");
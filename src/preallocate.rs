@@ -0,0 +1,207 @@
+use std::cmp;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use crate::torrent::FileInfo;
+
+/// zeros are written in chunks of this size, when the filesystem can not allocate the space by itself
+const ZERO_CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub(crate) enum PreallocationMode {
+    /// files are created when the first piece is written to them, and grow with the writes
+    None,
+    /// files are resized up front, the space is allocated by the filesystem on the writes
+    #[default]
+    Sparse,
+    /// the space is allocated up front, so that the download can not run out of space in the middle
+    Full,
+}
+
+/// Blocks, the existing data is kept
+pub(crate) fn preallocate_file(file: &File, length: u64, mode: PreallocationMode) -> anyhow::Result<()> {
+    let current_length = file.metadata().context("failed to get file metadata")?.len();
+    match mode {
+        PreallocationMode::None => {},
+        PreallocationMode::Sparse => {
+            if current_length != length {
+                file.set_len(length).context("failed to set file length")?;
+            }
+        },
+        PreallocationMode::Full => {
+            if current_length > length {
+                file.set_len(length).context("failed to set file length")?;
+            }
+            if !allocate(file, length)? {
+                write_zeros(file, current_length, length)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Returns false if the filesystem does not support the allocation
+#[cfg(target_os = "linux")]
+fn allocate(file: &File, length: u64) -> anyhow::Result<bool> {
+    use std::ffi::{c_int, c_long};
+    use std::os::fd::AsRawFd;
+    const EOPNOTSUPP: i32 = 95;
+    extern "C" {
+        fn fallocate(fd: c_int, mode: c_int, offset: c_long, length: c_long) -> c_int;
+    }
+    if length == 0 {
+        return Ok(true);
+    }
+    let Ok(c_length) = c_long::try_from(length) else {
+        return Ok(false);
+    };
+    let result = unsafe { fallocate(file.as_raw_fd(), 0, 0, c_length) };
+    if result == 0 {
+        return Ok(true);
+    }
+    let error = std::io::Error::last_os_error();
+    if error.raw_os_error() == Some(EOPNOTSUPP) {
+        return Ok(false);
+    }
+    Err(error).context("failed to allocate file space")
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allocate(_file: &File, _length: u64) -> anyhow::Result<bool> {
+    Ok(false)
+}
+
+/// The portable fallback only fills the space after the existing data, the holes in the existing data are kept
+fn write_zeros(mut file: &File, from: u64, to: u64) -> anyhow::Result<()> {
+    file.seek(SeekFrom::Start(from)).context("failed to seek file for preallocation")?;
+    let zeros = vec![0; ZERO_CHUNK_SIZE];
+    let mut pos = from;
+    while pos < to {
+        let length = cmp::min(ZERO_CHUNK_SIZE as u64, to - pos) as usize;
+        file.write_all(&zeros[..length]).context("failed to write file for preallocation")?;
+        pos += length as u64;
+    }
+    Ok(())
+}
+
+/// Fails if the filesystem does not have enough space for the parts of the files that are not allocated yet.
+/// Assumes that all files are on the same filesystem
+pub(crate) fn check_free_space(files: &[FileInfo]) -> anyhow::Result<()> {
+    let Some(first_file) = files.first() else {
+        return Ok(());
+    };
+    let mut required = 0;
+    for file in files {
+        let allocated = match std::fs::metadata(&file.path) {
+            Ok(metadata) => allocated_length(&metadata),
+            Err(_) => 0,
+        };
+        required += (file.length as u64).saturating_sub(allocated);
+    }
+    ensure_free_space(&first_file.path, required)
+}
+
+fn ensure_free_space(path: &Path, required: u64) -> anyhow::Result<()> {
+    // the file and its parent directories might not exist yet
+    let Some(existing) = path.ancestors().find(|path| path.exists()) else {
+        return Ok(());
+    };
+    let Some(available) = available_space(existing)? else {
+        return Ok(());
+    };
+    if available < required {
+        bail!("not enough space on the disk of {}: {required} bytes are required, but only {available} are available", existing.display());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn allocated_length(metadata: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    // blocks are always counted in 512 byte units
+    metadata.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn allocated_length(metadata: &std::fs::Metadata) -> u64 {
+    metadata.len()
+}
+
+/// Returns None if the free space can not be checked on this platform
+#[cfg(target_os = "linux")]
+fn available_space(path: &Path) -> anyhow::Result<Option<u64>> {
+    use std::ffi::{c_char, c_int, c_ulong, CString};
+    use std::os::unix::ffi::OsStrExt;
+    /// only the first fields are used, the rest is padded generously, because it differs between the libc versions
+    #[repr(C)]
+    struct StatVfs {
+        f_bsize: c_ulong,
+        f_frsize: c_ulong,
+        f_blocks: c_ulong,
+        f_bfree: c_ulong,
+        f_bavail: c_ulong,
+        _rest: [c_ulong; 16],
+    }
+    extern "C" {
+        fn statvfs(path: *const c_char, buf: *mut StatVfs) -> c_int;
+    }
+    let c_path = CString::new(path.as_os_str().as_bytes()).context("path contains a nul byte")?;
+    let mut stat = StatVfs{ f_bsize: 0, f_frsize: 0, f_blocks: 0, f_bfree: 0, f_bavail: 0, _rest: [0; 16] };
+    let result = unsafe { statvfs(c_path.as_ptr(), &mut stat) };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error()).context(format!("failed to get free space of {}", path.display()));
+    }
+    // c_ulong is only 32 bits on some platforms
+    #[allow(clippy::unnecessary_cast)]
+    let available = stat.f_bavail as u64 * stat.f_frsize as u64;
+    Ok(Some(available))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn available_space(_path: &Path) -> anyhow::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_preallocate_file() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("data");
+        std::fs::write(&path, b"existing")?;
+        let file = File::options().read(true).write(true).open(&path)?;
+
+        preallocate_file(&file, 100, PreallocationMode::None)?;
+        assert_eq!(8, file.metadata()?.len());
+        preallocate_file(&file, 100, PreallocationMode::Sparse)?;
+        assert_eq!(100, file.metadata()?.len());
+        preallocate_file(&file, 10_000, PreallocationMode::Full)?;
+        assert_eq!(10_000, file.metadata()?.len());
+        assert!(allocated_length(&file.metadata()?) >= 10_000, "space should be allocated");
+        assert_eq!(b"existing", &std::fs::read(&path)?[..8], "existing data should be kept");
+        preallocate_file(&file, 0, PreallocationMode::Full)?;
+        assert_eq!(0, file.metadata()?.len(), "zero length should be supported");
+
+        let path = dir.path().join("zeros");
+        let file = File::create(&path)?;
+        write_zeros(&file, 0, ZERO_CHUNK_SIZE as u64 + 10)?;
+        assert_eq!(ZERO_CHUNK_SIZE as u64 + 10, file.metadata()?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_free_space() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("missing/dir/data");
+        ensure_free_space(&path, 1)?;
+        if available_space(dir.path())?.is_some() {
+            let result = ensure_free_space(&path, u64::MAX);
+            assert!(result.is_err(), "huge torrent should not fit");
+        }
+        Ok(())
+    }
+}
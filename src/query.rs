@@ -0,0 +1,181 @@
+use std::borrow::Cow;
+use anyhow::{bail, Context};
+use crate::custom_bencode::{Value, BYTES_MARKER};
+
+/// A single part of the query
+enum Step<'q> {
+    /// Non-utf8 keys are written as `$bytes:<hex>`, like in the json output
+    Key(Cow<'q, [u8]>),
+    /// Negative indexes count from the end of the list
+    Index(i64),
+    /// Length of a string, list or dict
+    Len,
+    /// Keys of a dict, as a list
+    Keys,
+}
+
+/// Selects a part of the value. Keys are separated with dots, list items are selected with `[index]`,
+/// and filters are applied with `|`, like `info.files[2].path` or `info.pieces|len`.
+/// Keys with dots or brackets in them are quoted, like `info["name.utf-8"]`, `\"` and `\\` are escapes in the quotes.
+/// An empty query or `.` selects the whole value
+pub(crate) fn query_value<'a>(value: &Value<'a>, query: &str) -> anyhow::Result<Value<'a>> {
+    apply_steps(value, &parse_query(query)?, query)
+}
+
+fn apply_steps<'a>(value: &Value<'a>, steps: &[(Step, usize)], query: &str) -> anyhow::Result<Value<'a>> {
+    let Some(((step, end), steps)) = steps.split_first() else {
+        return Ok(value.clone());
+    };
+    let path = &query[..*end];
+    match step {
+        Step::Key(key) => {
+            let dict = value.as_dict().context(format!("{path} can not be selected from {}", value.get_variant_name()))?;
+            let value = dict.get(key.as_ref()).context(format!("{path} is not found"))?;
+            apply_steps(value, steps, query)
+        },
+        Step::Index(index) => {
+            let list = value.as_list().context(format!("{path} can not be selected from {}", value.get_variant_name()))?;
+            let position = if *index < 0 { list.len() as i64 + index } else { *index };
+            let value = usize::try_from(position)
+                .ok()
+                .and_then(|position| list.get(position))
+                .context(format!("{path} is out of range, the list has {} items", list.len()))?;
+            apply_steps(value, steps, query)
+        },
+        Step::Len => {
+            let length = match value {
+                Value::Str(str) => str.len(),
+                Value::List(list) => list.len(),
+                Value::Dict(dict) => dict.len(),
+                Value::Int(_) => bail!("{path} can not be applied to an int"),
+            };
+            apply_steps(&Value::Int(length as i64), steps, query)
+        },
+        Step::Keys => {
+            let dict = value.as_dict().context(format!("{path} can not be applied to {}", value.get_variant_name()))?;
+            let keys = Value::List(dict.keys().map(|key| Value::Str(key)).collect());
+            apply_steps(&keys, steps, query)
+        },
+    }
+}
+
+/// Returns the steps with the end of each step in the query, for the errors
+fn parse_query(query: &str) -> anyhow::Result<Vec<(Step<'_>, usize)>> {
+    let mut steps = vec![];
+    let mut pos = 0;
+    let mut expect_key = true;
+    if query.starts_with('.') {
+        pos += 1;
+        // a single dot is the whole value
+        expect_key = query.len() > 1;
+    }
+    while pos < query.len() {
+        let rest = &query[pos..];
+        let step = if let Some(rest) = rest.strip_prefix("[\"") {
+            let Some((key, length)) = parse_quoted_key(rest) else {
+                bail!("quoted key at {pos} is not closed");
+            };
+            pos += length + 2;
+            Step::Key(Cow::Owned(key))
+        } else if let Some(rest) = rest.strip_prefix('[') {
+            let Some(length) = rest.find(']') else {
+                bail!("index at {pos} is not closed");
+            };
+            let index = rest[..length].trim().parse().context(format!("invalid index at {pos}"))?;
+            pos += length + 2;
+            Step::Index(index)
+        } else if let Some(rest) = rest.strip_prefix('|') {
+            let length = rest.find(['.', '[', '|']).unwrap_or(rest.len());
+            let step = match rest[..length].trim() {
+                "len" => Step::Len,
+                "keys" => Step::Keys,
+                filter => bail!("unknown filter {filter} at {pos}, expected len or keys"),
+            };
+            pos += length + 1;
+            step
+        } else {
+            let rest = match rest.strip_prefix('.') {
+                Some(rest) => {
+                    pos += 1;
+                    rest
+                },
+                None if expect_key => rest,
+                None => bail!("unexpected {rest} at {pos}"),
+            };
+            let length = rest.find(['.', '[', '|']).unwrap_or(rest.len());
+            if length == 0 {
+                bail!("empty key at {pos}");
+            }
+            let key = &rest[..length];
+            let key = match key.strip_prefix(BYTES_MARKER).and_then(|key| key.strip_prefix(':')) {
+                Some(hex_key) => Cow::Owned(hex::decode(hex_key).context(format!("key at {pos} should be hex after {BYTES_MARKER}:"))?),
+                None => Cow::Borrowed(key.as_bytes()),
+            };
+            pos += length;
+            Step::Key(key)
+        };
+        steps.push((step, pos));
+        expect_key = false;
+    }
+    Ok(steps)
+}
+
+/// Returns the key and the length of the query that it takes, including the closing `"]`
+fn parse_quoted_key(query: &str) -> Option<(Vec<u8>, usize)> {
+    let mut key = String::new();
+    let mut chars = query.char_indices();
+    while let Some((pos, char)) = chars.next() {
+        match char {
+            '\\' => key.push(chars.next()?.1),
+            '"' => return query[pos + 1..].starts_with(']').then(|| (key.into_bytes(), pos + 2)),
+            char => key.push(char),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use crate::custom_bdecode::decode_value;
+    use crate::custom_bencode::{json_encode_value, BinaryFormat};
+    use super::*;
+
+    #[test]
+    fn test_query_value() -> anyhow::Result<()> {
+        let input = b"d4:infod5:filesld6:lengthi1e4:pathl1:aeed6:lengthi2e4:pathl3:dir1:beee4:name4:test6:pieces4:\xff\x00\xff\x00e2:\xff\x01i7ee";
+        let value = decode_value(input)?;
+        let query = |query: &str| -> anyhow::Result<String> {
+            Ok(json_encode_value(&query_value(&value, query)?, BinaryFormat::Bytes))
+        };
+        assert_eq!(json_encode_value(&value, BinaryFormat::Bytes), query("")?);
+        assert_eq!(json_encode_value(&value, BinaryFormat::Bytes), query(".")?);
+        assert_eq!(r#""test""#, query("info.name")?);
+        assert_eq!(r#""test""#, query(".info.name")?);
+        assert_eq!(r#"["dir","b"]"#, query("info.files[1].path")?);
+        assert_eq!(r#""b""#, query("info.files[-1].path[-1]")?);
+        assert_eq!("4", query("info.pieces|len")?);
+        assert_eq!("2", query("info.files|len")?);
+        assert_eq!(r#"["files","name","pieces"]"#, query("info|keys")?);
+        assert_eq!(r#""files""#, query("info|keys[0]")?);
+        assert_eq!("7", query("$bytes:ff01")?);
+        assert_eq!(r#""test""#, query(r#"info["name"]"#)?);
+        let quoted = decode_value(b"d6:a.b[0]d2:\"\\1:cee")?;
+        assert_eq!(r#""c""#, json_encode_value(&query_value(&quoted, r#"["a.b[0]"]["\"\\"]"#)?, BinaryFormat::Bytes));
+
+        for (query, error) in [
+            ("info.missing", "info.missing is not found"),
+            ("info.files[2]", "info.files[2] is out of range"),
+            ("info.name.first", "info.name.first can not be selected from string"),
+            ("info.files[0].length|len", "|len can not be applied to an int"),
+            ("info|size", "unknown filter size"),
+            ("info.files[x]", "invalid index"),
+            ("info..name", "empty key"),
+            (r#"info["name"#, "quoted key at 4 is not closed"),
+            (r#"info["name"x]"#, "quoted key at 4 is not closed"),
+        ] {
+            let result = query_value(&value, query).err().context(format!("{query} should fail"))?;
+            assert!(format!("{result:#}").contains(error), "{query} failed with {result:#}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use crate::custom_bdecode::from_bytes;
+use crate::custom_bencode::to_bytes;
+use crate::peer::{bitfield_length, set_piece, validate_bitfield};
+use crate::storage::Storage;
+use crate::torrent::{HASH_RAW_LENGTH, TorrentInfo};
+
+pub(crate) const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// State of a download, that is saved next to the data to skip the recheck when the download is restarted
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct ResumeData {
+    #[serde(with = "serde_bytes")]
+    info_hash: [u8; HASH_RAW_LENGTH],
+    /// pieces that were verified and written to the data files
+    #[serde(with = "serde_bytes")]
+    bitfield: Vec<u8>,
+    files: Vec<ResumeFile>,
+}
+
+/// Used to check that the data was not modified after the resume file was saved
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct ResumeFile {
+    length: u64,
+    /// nanoseconds since the unix epoch
+    mtime: u64,
+}
+impl ResumeFile {
+    /// Files that do not exist yet are recorded as empty, they are not created without preallocation until a piece is written
+    async fn from_path(path: &Path) -> anyhow::Result<Self> {
+        let metadata = match tokio::fs::metadata(path).await {
+            Ok(metadata) => metadata,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Self { length: 0, mtime: 0 }),
+            Err(error) => return Err(error).context(format!("failed to get metadata of {}", path.display())),
+        };
+        let mtime = metadata.modified().context("failed to get file modification time")?;
+        let mtime = mtime.duration_since(UNIX_EPOCH).context("file modification time is before the unix epoch")?;
+        let file = Self {
+            length: metadata.len(),
+            mtime: mtime.as_nanos() as u64,
+        };
+        Ok(file)
+    }
+}
+
+pub(crate) fn resume_file_path(data_path: impl AsRef<Path>) -> PathBuf {
+    let mut path = data_path.as_ref().as_os_str().to_owned();
+    path.push(".resume");
+    PathBuf::from(path)
+}
+
+/// Returns the verified pieces from the resume file, or None if it is missing or does not match the data
+async fn load_resume_bitfield(
+    resume_path: &Path,
+    info_hash: &[u8; HASH_RAW_LENGTH],
+    pieces_count: u32,
+    data_paths: &[PathBuf],
+) -> Option<Vec<u8>> {
+    let contents = tokio::fs::read(resume_path).await.ok()?;
+    let resume: ResumeData = from_bytes(&contents).ok()?;
+    if resume.info_hash != *info_hash || validate_bitfield(&resume.bitfield, pieces_count).is_err() {
+        return None;
+    }
+    if resume.files.len() != data_paths.len() {
+        return None;
+    }
+    for (expected, path) in resume.files.iter().zip(data_paths) {
+        let actual = ResumeFile::from_path(path).await.ok()?;
+        if actual != *expected {
+            return None;
+        }
+    }
+    Some(resume.bitfield)
+}
+
+/// Hashes every piece of the data, and returns the bitfield of the valid ones
+pub(crate) async fn recheck_pieces(storage: &impl Storage, info: &TorrentInfo) -> anyhow::Result<Vec<u8>> {
+    let mut bitfield = vec![0; bitfield_length(info.pieces.len() as u32)];
+    for piece_info in info.get_all_pieces_info() {
+        if storage.hash_piece(&piece_info).await? == Some(piece_info.hash) {
+            set_piece(piece_info.index, &mut bitfield);
+        }
+    }
+    Ok(bitfield)
+}
+
+/// Collects the pieces that are completed during the download, and saves them into the resume file
+pub(crate) struct ResumeWriter {
+    /// None for the storages that do not keep the data between the runs, only the storage is flushed on save then
+    resume_path: Option<PathBuf>,
+    data_paths: Vec<PathBuf>,
+    info_hash: [u8; HASH_RAW_LENGTH],
+    bitfield: std::sync::Mutex<Vec<u8>>,
+}
+impl ResumeWriter {
+    pub fn new(resume_path: PathBuf, data_paths: Vec<PathBuf>, info_hash: [u8; HASH_RAW_LENGTH], bitfield: Vec<u8>) -> Self {
+        Self {
+            resume_path: Some(resume_path),
+            data_paths,
+            info_hash,
+            bitfield: std::sync::Mutex::new(bitfield),
+        }
+    }
+
+    pub fn without_file(info_hash: [u8; HASH_RAW_LENGTH], bitfield: Vec<u8>) -> Self {
+        Self {
+            resume_path: None,
+            data_paths: vec![],
+            info_hash,
+            bitfield: std::sync::Mutex::new(bitfield),
+        }
+    }
+
+    pub fn bitfield(&self) -> Vec<u8> {
+        self.bitfield.lock().expect("poisoned lock").clone()
+    }
+
+    /// Should be called only after the piece is written to the storage
+    pub fn set_piece(&self, index: u32) {
+        set_piece(index, &mut self.bitfield.lock().expect("poisoned lock"));
+    }
+
+    pub async fn save(&self, storage: &impl Storage) -> anyhow::Result<()> {
+        // the bitfield is taken before the flush, so that it does not contain pieces that are not persisted yet.
+        // writes after the flush can make the resume file stale, which only leads to a recheck
+        let bitfield = self.bitfield.lock().expect("poisoned lock").clone();
+        storage.flush().await?;
+        let Some(resume_path) = &self.resume_path else {
+            return Ok(());
+        };
+        let mut files = vec![];
+        for path in &self.data_paths {
+            files.push(ResumeFile::from_path(path).await?);
+        }
+
+        let resume = ResumeData {
+            info_hash: self.info_hash,
+            bitfield,
+            files,
+        };
+        let contents = to_bytes(&resume).context("failed to encode resume data")?;
+        // written into a temporary file first, so that an interrupted save does not leave a broken resume file
+        let mut temp_path = resume_path.clone().into_os_string();
+        temp_path.push(".tmp");
+        tokio::fs::write(&temp_path, contents).await.context("failed to write resume file")?;
+        tokio::fs::rename(&temp_path, resume_path).await.context("failed to replace resume file")?;
+        Ok(())
+    }
+}
+
+/// Returns the bitfield of the pieces that are already present in the storage
+pub(crate) async fn load_verified_pieces(
+    storage: &impl Storage,
+    info: &TorrentInfo,
+    info_hash: &[u8; HASH_RAW_LENGTH],
+    resume_path: &Path,
+    data_paths: &[PathBuf],
+) -> anyhow::Result<Vec<u8>> {
+    let pieces_count = info.pieces.len() as u32;
+    if let Some(bitfield) = load_resume_bitfield(resume_path, info_hash, pieces_count, data_paths).await {
+        return Ok(bitfield);
+    }
+    recheck_pieces(storage, info).await
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use sha1::{Digest, Sha1};
+    use crate::peer::piece_exists;
+    use crate::seed::test::{get_data, PIECE_LENGTH};
+    use crate::storage::{FileStorage, MemoryStorage};
+    use super::*;
+
+    fn create_info(data: &[u8]) -> TorrentInfo {
+        let pieces = data
+            .chunks(PIECE_LENGTH as usize)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        TorrentInfo::new_single_file("test", data.len() as u32, PIECE_LENGTH, pieces)
+    }
+
+    #[tokio::test]
+    async fn test_recheck_pieces() -> anyhow::Result<()> {
+        let mut data = get_data(PIECE_LENGTH as usize * 2 + 100);
+        let info = create_info(&data);
+        data[PIECE_LENGTH as usize + 1] ^= 1;
+        let storage = MemoryStorage::with_data(data);
+        let bitfield = recheck_pieces(&storage, &info).await?;
+        assert_eq!(vec![0b10100000], bitfield);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resume() -> anyhow::Result<()> {
+        let data = get_data(PIECE_LENGTH as usize * 3);
+        let info = create_info(&data);
+        let info_hash = [1; HASH_RAW_LENGTH];
+        let dir = tempfile::tempdir()?;
+        let data_path = dir.path().join("data");
+        let resume_path = resume_file_path(&data_path);
+        let data_paths = vec![data_path.clone()];
+
+        let storage = FileStorage::new(info.get_files_info(&data_path));
+        storage.preallocate().await?;
+        let bitfield = load_verified_pieces(&storage, &info, &info_hash, &resume_path, &data_paths).await?;
+        assert_eq!(vec![0], bitfield, "new file should not have any pieces");
+
+        // only the first piece is written, but the resume file claims the second one too
+        let piece = info.get_piece_info(0)?;
+        storage.write_piece(&piece, &data[..PIECE_LENGTH as usize]).await?;
+        let writer = ResumeWriter::new(resume_path.clone(), data_paths.clone(), info_hash, bitfield);
+        writer.set_piece(0);
+        writer.set_piece(1);
+        writer.save(&storage).await?;
+
+        let bitfield = load_verified_pieces(&storage, &info, &info_hash, &resume_path, &data_paths).await?;
+        assert!(piece_exists(1, &bitfield), "valid resume file should be trusted without a recheck");
+        let bitfield = load_verified_pieces(&storage, &info, &[2; HASH_RAW_LENGTH], &resume_path, &data_paths).await?;
+        assert_eq!(vec![0b10000000], bitfield, "resume file for another torrent should be ignored");
+
+        std::fs::OpenOptions::new().append(true).open(&data_path)?.write_all(&[0])?;
+        let bitfield = load_verified_pieces(&storage, &info, &info_hash, &resume_path, &data_paths).await?;
+        assert_eq!(vec![0b10000000], bitfield, "modified data should be rechecked");
+
+        std::fs::write(&resume_path, b"garbage")?;
+        let bitfield = load_verified_pieces(&storage, &info, &info_hash, &resume_path, &data_paths).await?;
+        assert_eq!(vec![0b10000000], bitfield, "broken resume file should be ignored");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resume_missing_file() -> anyhow::Result<()> {
+        let data = get_data(PIECE_LENGTH as usize * 2);
+        let pieces_count = create_info(&data).pieces.len() as u32;
+        let info_hash = [1; HASH_RAW_LENGTH];
+        let dir = tempfile::tempdir()?;
+        let data_path = dir.path().join("data");
+        let resume_path = resume_file_path(&data_path);
+        let data_paths = vec![data_path.clone()];
+
+        // nothing was written and nothing was preallocated, so the data file does not exist
+        let storage = MemoryStorage::new(data.len() as u32);
+        let writer = ResumeWriter::new(resume_path.clone(), data_paths.clone(), info_hash, vec![0]);
+        writer.save(&storage).await?;
+        assert_eq!(Some(vec![0]), load_resume_bitfield(&resume_path, &info_hash, pieces_count, &data_paths).await);
+
+        std::fs::write(&data_path, &data)?;
+        assert_eq!(None, load_resume_bitfield(&resume_path, &info_hash, pieces_count, &data_paths).await, "file that was created later should be rechecked");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_preallocate_keeps_data() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let data_path = dir.path().join("data");
+        std::fs::write(&data_path, b"existing")?;
+        let info = TorrentInfo::new_single_file("test", 8, PIECE_LENGTH, vec![[0; HASH_RAW_LENGTH]]);
+        FileStorage::new(info.get_files_info(&data_path)).preallocate().await?;
+        assert_eq!(b"existing".to_vec(), std::fs::read(&data_path)?);
+        Ok(())
+    }
+}
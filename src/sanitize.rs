@@ -0,0 +1,102 @@
+use std::path::PathBuf;
+
+/// most filesystems do not allow longer names
+const MAX_NAME_LENGTH: usize = 255;
+/// extensions up to this length are kept when a long name is truncated
+const MAX_EXTENSION_LENGTH: usize = 16;
+/// not allowed on windows, and the separators on all platforms
+const RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+/// device names on windows, they can not be used as file names even with an extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+/// used instead of the names that are empty after the sanitisation
+const EMPTY_NAME: &str = "_";
+
+/// Rewrites a single path component from the torrent, so that it's a valid file name on all platforms.
+/// Returns None for the components that should be dropped: empty ones, the current and the parent directory
+pub(crate) fn sanitize_component(component: &str) -> Option<String> {
+    let mut name = component
+        .chars()
+        .map(|char| if char.is_control() || RESERVED_CHARS.contains(&char) { '_' } else { char })
+        .collect::<String>();
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+    // windows silently removes the trailing dots and spaces, which can make different names refer to the same file
+    let trimmed_length = name.trim_end_matches(['.', ' ']).len();
+    if trimmed_length < name.len() {
+        name.truncate(trimmed_length);
+        name.push('_');
+    }
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+        name.insert(0, '_');
+    }
+    Some(truncate_name(name))
+}
+
+/// Keeps the extension, if it's short enough
+fn truncate_name(name: String) -> String {
+    if name.len() <= MAX_NAME_LENGTH {
+        return name;
+    }
+    let extension = match name.rfind('.') {
+        Some(pos) if pos > 0 && name.len() - pos <= MAX_EXTENSION_LENGTH => &name[pos..],
+        _ => "",
+    };
+    let mut stem_length = MAX_NAME_LENGTH - extension.len();
+    while !name.is_char_boundary(stem_length) {
+        stem_length -= 1;
+    }
+    format!("{}{extension}", &name[..stem_length])
+}
+
+/// Builds a relative path from the path components of the torrent. The path can not be absolute
+/// and can not contain parent directories, so it always stays inside the directory it is joined to
+pub(crate) fn sanitize_path<S: AsRef<str>>(components: &[S]) -> PathBuf {
+    let path = components
+        .iter()
+        .filter_map(|component| sanitize_component(component.as_ref()))
+        .collect::<PathBuf>();
+    if path.as_os_str().is_empty() {
+        return PathBuf::from(EMPTY_NAME);
+    }
+    path
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_component() {
+        assert_eq!(Some("file.txt".to_string()), sanitize_component("file.txt"));
+        assert_eq!(None, sanitize_component(""));
+        assert_eq!(None, sanitize_component("."));
+        assert_eq!(None, sanitize_component(".."));
+        assert_eq!(Some("_etc_passwd".to_string()), sanitize_component("/etc/passwd"));
+        assert_eq!(Some(".._.._x".to_string()), sanitize_component("..\\..\\x"));
+        assert_eq!(Some("a_b_c_".to_string()), sanitize_component("a:b?c\n"));
+        assert_eq!(Some("name_".to_string()), sanitize_component("name. "));
+        assert_eq!(Some("_con.txt".to_string()), sanitize_component("con.txt"));
+        assert_eq!(Some("console".to_string()), sanitize_component("console"));
+
+        let long_name = format!("{}.mkv", "a".repeat(300));
+        let sanitized = sanitize_component(&long_name).unwrap();
+        assert_eq!(MAX_NAME_LENGTH, sanitized.len());
+        assert!(sanitized.ends_with(".mkv"), "extension should be kept");
+        let sanitized = sanitize_component(&"ä".repeat(200)).unwrap();
+        assert!(sanitized.len() <= MAX_NAME_LENGTH, "name should be truncated on a char boundary");
+    }
+
+    #[test]
+    fn test_sanitize_path() {
+        assert_eq!(PathBuf::from("a/b"), sanitize_path(&["a", "", ".", "b"]));
+        assert_eq!(PathBuf::from("etc/passwd"), sanitize_path(&["..", "..", "etc", "passwd"]));
+        assert_eq!(PathBuf::from(EMPTY_NAME), sanitize_path(&["..", ""]));
+        assert_eq!(PathBuf::from(EMPTY_NAME), sanitize_path::<&str>(&[]));
+    }
+}
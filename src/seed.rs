@@ -0,0 +1,122 @@
+use anyhow::bail;
+use crate::peer::{piece_exists, BlockRequest, MAX_REQUEST_LENGTH};
+use crate::resume::recheck_pieces;
+use crate::storage::Storage;
+use crate::torrent::TorrentInfo;
+
+/// Local data of a completed torrent, that is served to other peers
+pub(crate) struct SeedData<S> {
+    info: TorrentInfo,
+    pub bitfield: Vec<u8>,
+    storage: S,
+}
+impl<S: Storage> SeedData<S> {
+    pub async fn open(info: TorrentInfo, storage: S) -> anyhow::Result<Self> {
+        let pieces_count = info.pieces.len() as u32;
+        let bitfield = recheck_pieces(&storage, &info).await?;
+        let corrupt_count = (0..pieces_count).filter(|&index| !piece_exists(index, &bitfield)).count();
+        if corrupt_count > 0 {
+            bail!("data does not match the torrent, {corrupt_count} of {pieces_count} pieces are missing or have invalid hashes");
+        }
+
+        let seed = Self {
+            info,
+            bitfield,
+            storage,
+        };
+        Ok(seed)
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn pieces_count(&self) -> u32 {
+        self.info.pieces.len() as u32
+    }
+
+    pub fn validate_request(&self, request: &BlockRequest) -> anyhow::Result<u64> {
+        let BlockRequest{ index, begin, length } = *request;
+        if length == 0 || length > MAX_REQUEST_LENGTH {
+            bail!("invalid request length {length}, max allowed is {MAX_REQUEST_LENGTH}");
+        }
+        let piece_info = self.info.get_piece_info(index)?;
+        if !piece_exists(index, &self.bitfield) {
+            bail!("requested piece {index} that we do not have");
+        }
+        let Some(end) = begin.checked_add(length) else {
+            bail!("request for piece {index} is out of range");
+        };
+        if end > piece_info.length {
+            bail!("request for piece {index} ends at {end}, but piece length is {}", piece_info.length);
+        }
+        Ok(piece_info.file_start_pos as u64 + begin as u64)
+    }
+
+    pub async fn read_block(&self, request: &BlockRequest) -> anyhow::Result<Vec<u8>> {
+        self.validate_request(request)?;
+        let piece_info = self.info.get_piece_info(request.index)?;
+        self.storage.read_block(&piece_info, request.begin, request.length).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use sha1::{Digest, Sha1};
+    use crate::storage::{FileStorage, MemoryStorage};
+    use crate::torrent::HASH_RAW_LENGTH;
+    use super::*;
+
+    pub(crate) const PIECE_LENGTH: u32 = 32 * 1024;
+
+    pub(crate) async fn create_seed(data: &[u8]) -> anyhow::Result<SeedData<MemoryStorage>> {
+        let pieces = data
+            .chunks(PIECE_LENGTH as usize)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let info = TorrentInfo::new_single_file("test", data.len() as u32, PIECE_LENGTH, pieces);
+        SeedData::open(info, MemoryStorage::with_data(data.to_vec())).await
+    }
+
+    pub(crate) fn get_data(length: usize) -> Vec<u8> {
+        (0..length).map(|x| (x % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn test_validate_request() -> anyhow::Result<()> {
+        let seed = create_seed(&get_data(PIECE_LENGTH as usize + 100)).await?;
+        assert_eq!(vec![0b11000000], seed.bitfield);
+
+        let request = BlockRequest{ index: 0, begin: 0, length: MAX_REQUEST_LENGTH };
+        assert_eq!(0, seed.validate_request(&request)?);
+        let request = BlockRequest{ index: 1, begin: 50, length: 50 };
+        assert_eq!(PIECE_LENGTH as u64 + 50, seed.validate_request(&request)?);
+
+        let request = BlockRequest{ index: 0, begin: 0, length: MAX_REQUEST_LENGTH + 1 };
+        assert!(seed.validate_request(&request).is_err(), "oversized request should be rejected");
+        let request = BlockRequest{ index: 0, begin: 0, length: 0 };
+        assert!(seed.validate_request(&request).is_err(), "empty request should be rejected");
+        let request = BlockRequest{ index: 2, begin: 0, length: 10 };
+        assert!(seed.validate_request(&request).is_err(), "piece 2 should not exist");
+        let request = BlockRequest{ index: 1, begin: 51, length: 50 };
+        assert!(seed.validate_request(&request).is_err(), "request should not go past the end of the piece");
+        let request = BlockRequest{ index: 1, begin: u32::MAX, length: 50 };
+        assert!(seed.validate_request(&request).is_err(), "request should not overflow");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_open_corrupt_data() -> anyhow::Result<()> {
+        let info = TorrentInfo::new_single_file("test", 100, PIECE_LENGTH, vec![[0; HASH_RAW_LENGTH]]);
+        let seed = SeedData::open(info, MemoryStorage::with_data(get_data(100))).await;
+        assert!(seed.is_err(), "corrupt data should not be seeded");
+
+        let dir = tempfile::tempdir()?;
+        let data = get_data(100);
+        let info = TorrentInfo::new_single_file("test", 100, PIECE_LENGTH, vec![Sha1::digest(&data).into()]);
+        let files = info.get_files_info(&dir.path().join("missing"));
+        let seed = SeedData::open(info, FileStorage::new(files)).await;
+        assert!(seed.is_err(), "missing data should not be seeded");
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context};
+
+/// files that are not completed yet get this suffix, when there is no incomplete directory
+const PART_SUFFIX: &str = ".part";
+/// rename fails with this error when the target is on another filesystem, it's the same on linux and macos
+const EXDEV: i32 = 18;
+
+/// Where the files are kept while they are downloaded. They are in the incomplete directory if it's set,
+/// keeping the same layout, and next to the final files with the part suffix otherwise
+pub(crate) fn staging_paths(final_paths: &[PathBuf], data_path: &Path, incomplete_dir: Option<&Path>) -> anyhow::Result<Vec<PathBuf>> {
+    let Some(incomplete_dir) = incomplete_dir else {
+        let paths = final_paths
+            .iter()
+            .map(|path| {
+                let mut path = path.clone().into_os_string();
+                path.push(PART_SUFFIX);
+                PathBuf::from(path)
+            })
+            .collect();
+        return Ok(paths);
+    };
+    let Some(data_name) = data_path.file_name() else {
+        bail!("save location {} does not end with a file name", data_path.display());
+    };
+    let staging_root = incomplete_dir.join(data_name);
+    let mut paths = vec![];
+    for path in final_paths {
+        let relative = path.strip_prefix(data_path).context("file is outside of the save location")?;
+        // joining an empty path would add a trailing separator
+        let path = if relative.as_os_str().is_empty() { staging_root.clone() } else { staging_root.join(relative) };
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Renames the file, or copies it if it's on another filesystem. The target is replaced atomically in both cases
+async fn move_file(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = to.parent() {
+        tokio::fs::create_dir_all(parent).await.context(format!("failed to create directory {}", parent.display()))?;
+    }
+    match tokio::fs::rename(from, to).await {
+        Ok(()) => return Ok(()),
+        Err(error) if error.raw_os_error() == Some(EXDEV) => {},
+        Err(error) => return Err(error).context(format!("failed to move {} to {}", from.display(), to.display())),
+    }
+
+    // the copy is made next to the target, so that the target is never seen half-written
+    let mut temp_path = to.to_path_buf().into_os_string();
+    temp_path.push(PART_SUFFIX);
+    let copy_error = format!("failed to copy {} to {}", from.display(), to.display());
+    tokio::fs::copy(from, &temp_path).await.context(copy_error.clone())?;
+    let copied = tokio::fs::File::open(&temp_path).await.context(copy_error.clone())?;
+    copied.sync_all().await.context(copy_error)?;
+    tokio::fs::rename(&temp_path, to).await.context(format!("failed to move the copy to {}", to.display()))?;
+    tokio::fs::remove_file(from).await.context(format!("failed to remove {} after copying", from.display()))?;
+    Ok(())
+}
+
+/// Files that were never written are only allowed to be missing if they are empty
+pub(crate) async fn move_data_file(from: &Path, to: &Path, length: u32) -> anyhow::Result<()> {
+    match tokio::fs::metadata(from).await {
+        Ok(_) => move_file(from, to).await,
+        Err(error) if error.kind() == ErrorKind::NotFound && length == 0 => {
+            if let Some(parent) = to.parent() {
+                tokio::fs::create_dir_all(parent).await.context(format!("failed to create directory {}", parent.display()))?;
+            }
+            tokio::fs::File::create(to).await.context(format!("failed to create {}", to.display()))?;
+            Ok(())
+        },
+        Err(error) => Err(error).context(format!("failed to move {}", from.display())),
+    }
+}
+
+pub(crate) async fn move_files(from: &[PathBuf], to: &[PathBuf], lengths: &[u32]) -> anyhow::Result<()> {
+    for ((from, to), length) in from.iter().zip(to).zip(lengths) {
+        move_data_file(from, to, *length).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_staging_paths() -> anyhow::Result<()> {
+        let final_paths = vec![PathBuf::from("out/data")];
+        let paths = staging_paths(&final_paths, Path::new("out/data"), None)?;
+        assert_eq!(vec![PathBuf::from("out/data.part")], paths);
+        let paths = staging_paths(&final_paths, Path::new("out/data"), Some(Path::new("incomplete")))?;
+        assert_eq!(vec![PathBuf::from("incomplete/data")], paths);
+
+        let final_paths = vec![PathBuf::from("out/dir/a"), PathBuf::from("out/dir/sub/b")];
+        let paths = staging_paths(&final_paths, Path::new("out/dir"), Some(Path::new("incomplete")))?;
+        assert_eq!(vec![PathBuf::from("incomplete/dir/a"), PathBuf::from("incomplete/dir/sub/b")], paths);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_move_files() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let from = vec![dir.path().join("staging/a"), dir.path().join("staging/empty")];
+        let to = vec![dir.path().join("final/sub/a"), dir.path().join("final/empty")];
+        std::fs::create_dir(dir.path().join("staging"))?;
+        std::fs::write(&from[0], b"data")?;
+
+        move_files(&from, &to, &[4, 0]).await?;
+        assert_eq!(b"data".to_vec(), std::fs::read(&to[0])?);
+        assert!(!from[0].exists(), "source should be removed");
+        assert!(to[1].exists(), "empty file should be created");
+
+        let result = move_files(&from, &to, &[4, 0]).await;
+        assert!(result.is_err(), "missing non-empty file should fail the move");
+        Ok(())
+    }
+}
@@ -0,0 +1,702 @@
+use std::cmp;
+use std::future::Future;
+use std::io::{ErrorKind, SeekFrom};
+use std::path::PathBuf;
+use std::sync::Arc;
+use anyhow::{bail, Context};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard};
+use crate::cache::{CacheStats, PieceCache, DEFAULT_CACHE_SIZE};
+use crate::hasher::HashPool;
+use crate::partfile::PartFile;
+use crate::preallocate::{check_free_space, preallocate_file, PreallocationMode};
+use crate::staging::move_data_file;
+use crate::torrent::{file_segments, FileInfo, PieceInfo, HASH_RAW_LENGTH};
+
+/// Where the torrent data is kept. Pieces are addressed by their position in the concatenated data of all files
+pub(crate) trait Storage: Send + Sync {
+    /// Reserves the space for all data, should be called before the first write
+    fn preallocate(&self) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    fn read_block(&self, piece: &PieceInfo, begin: u32, length: u32) -> impl Future<Output = anyhow::Result<Vec<u8>>> + Send;
+
+    /// Data should be already verified
+    fn write_piece(&self, piece: &PieceInfo, data: &[u8]) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Makes sure that all written pieces are persisted
+    fn flush(&self) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Hashes the stored piece data, None if the piece is not stored
+    fn hash_piece(&self, piece: &PieceInfo) -> impl Future<Output = anyhow::Result<Option<[u8; HASH_RAW_LENGTH]>>> + Send;
+}
+
+fn check_piece_data(piece: &PieceInfo, data: &[u8]) -> anyhow::Result<()> {
+    if data.len() != piece.length as usize {
+        bail!("piece {} has length {}, but got {} bytes of data", piece.index, piece.length, data.len());
+    }
+    Ok(())
+}
+
+fn check_block_range(piece: &PieceInfo, begin: u32, length: u32) -> anyhow::Result<()> {
+    let Some(end) = begin.checked_add(length) else {
+        bail!("block at {begin} with length {length} is out of range of piece {}", piece.index);
+    };
+    if end > piece.length {
+        bail!("block at {begin} with length {length} is out of range of piece {}", piece.index);
+    }
+    Ok(())
+}
+
+/// Range of the torrent data that is in both the file and the piece
+fn overlap(info: &FileInfo, piece: &PieceInfo) -> (u64, u64) {
+    let piece_start = piece.file_start_pos as u64;
+    let start = cmp::max(info.start_pos as u64, piece_start);
+    let end = cmp::min(info.end_pos(), piece_start + piece.length as u64);
+    (start, end)
+}
+
+/// The path is kept next to the open file, because the files can be moved while the storage is used
+struct FileHandle {
+    path: PathBuf,
+    /// files are opened on the first use, and kept open. The flag is set if the file is opened for writing
+    file: Option<(File, bool)>,
+}
+
+/// Data is kept in the torrent files, the same layout that is used by the other clients.
+/// Written pieces are cached and written back in batches, recently read pieces are cached for seeding
+pub(crate) struct FileStorage {
+    files: Vec<FileInfo>,
+    handles: Vec<Mutex<FileHandle>>,
+    cache: std::sync::Mutex<PieceCache>,
+    /// only one write back runs at a time, so that the same pieces are not written twice
+    write_back_lock: Mutex<()>,
+    preallocation: PreallocationMode,
+    /// data of the skipped files is kept in the part file, the files themselves are not created
+    skipped: Vec<bool>,
+    part_file: Option<PartFile>,
+    /// the part file only has the data of the first and the last pieces of the skipped files,
+    /// they are verified before they are merged into the files that are not skipped anymore
+    pieces: Vec<PieceInfo>,
+}
+impl FileStorage {
+    pub fn new(files: Vec<FileInfo>) -> Self {
+        Self::with_cache_size(files, DEFAULT_CACHE_SIZE)
+    }
+
+    pub fn with_cache_size(files: Vec<FileInfo>, cache_size: usize) -> Self {
+        let handles = files.iter().map(|info| Mutex::new(FileHandle{ path: info.path.clone(), file: None })).collect();
+        let skipped = vec![false; files.len()];
+        Self {
+            files,
+            handles,
+            cache: std::sync::Mutex::new(PieceCache::new(cache_size)),
+            write_back_lock: Mutex::new(()),
+            preallocation: PreallocationMode::default(),
+            skipped,
+            part_file: None,
+            pieces: vec![],
+        }
+    }
+
+    pub fn with_preallocation(mut self, preallocation: PreallocationMode) -> Self {
+        self.preallocation = preallocation;
+        self
+    }
+
+    /// The part file is merged into the files that are not skipped anymore, and removed when nothing is skipped
+    pub fn with_part_file(mut self, part_file: PartFile, skipped: Vec<bool>, pieces: Vec<PieceInfo>) -> Self {
+        self.part_file = Some(part_file);
+        self.skipped = skipped;
+        self.pieces = pieces;
+        self
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.lock().expect("poisoned lock").stats()
+    }
+
+    /// Moves the files to the new paths, the pieces can still be read and written while the files are moved.
+    /// Each file is closed before it is moved, and opened again from the new path on the next use
+    pub async fn move_files(&self, new_paths: Vec<PathBuf>) -> anyhow::Result<()> {
+        if new_paths.len() != self.handles.len() {
+            bail!("got {} paths for {} files", new_paths.len(), self.handles.len());
+        }
+        self.write_back().await?;
+        let lengths = self.files.iter().map(|info| info.length).collect::<Vec<_>>();
+        for (file_index, ((handle, new_path), length)) in self.handles.iter().zip(new_paths).zip(lengths).enumerate() {
+            let mut handle = handle.lock().await;
+            if self.skipped[file_index] {
+                // skipped files are not created, their data stays in the part file
+                handle.path = new_path;
+                continue;
+            }
+            if let Some((mut file, writable)) = handle.file.take() {
+                if writable {
+                    file.flush().await.context("failed to flush file")?;
+                    file.sync_data().await.context("failed to sync file")?;
+                }
+            }
+            move_data_file(&handle.path, &new_path, length).await?;
+            handle.path = new_path;
+        }
+        Ok(())
+    }
+
+    /// The files are only created when they are written to, so reads return None for the missing ones
+    async fn lock_file(&self, file_index: usize, write: bool) -> anyhow::Result<Option<MappedMutexGuard<'_, File>>> {
+        let mut handle = self.handles[file_index].lock().await;
+        let reopen = match &handle.file {
+            Some((_, writable)) => write && !writable,
+            None => true,
+        };
+        if reopen {
+            let path = &handle.path;
+            if write {
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await.context(format!("failed to create directory {}", parent.display()))?;
+                }
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(write)
+                .create(write)
+                .truncate(false)
+                .open(path)
+                .await;
+            let file = match file {
+                Ok(file) => file,
+                Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(error) => return Err(error).context(format!("failed to open {}", path.display())),
+            };
+            handle.file = Some((file, write));
+        }
+        let file = MutexGuard::map(handle, |handle| &mut handle.file.as_mut().expect("file should be opened").0);
+        Ok(Some(file))
+    }
+
+    /// Returns false if some of the data is not present on disk
+    async fn read_at(&self, pos: u64, buffer: &mut [u8]) -> anyhow::Result<bool> {
+        self.read_at_with(pos, buffer, &self.skipped).await
+    }
+
+    /// The data of the files that are set in `in_part_file` is read from the part file
+    async fn read_at_with(&self, pos: u64, buffer: &mut [u8], in_part_file: &[bool]) -> anyhow::Result<bool> {
+        for segment in file_segments(&self.files, pos, buffer.len() as u64) {
+            if let Some(part_file) = self.part_file.as_ref().filter(|_| in_part_file[segment.file_index]) {
+                let part_pos = self.files[segment.file_index].start_pos as u64 + segment.file_pos;
+                if !part_file.read_at(part_pos, &mut buffer[segment.data_range]).await? {
+                    return Ok(false);
+                }
+                continue;
+            }
+            let Some(mut file) = self.lock_file(segment.file_index, false).await? else {
+                return Ok(false);
+            };
+            let buffer = &mut buffer[segment.data_range];
+            let file_length = file.metadata().await.context("failed to get file metadata")?.len();
+            if file_length < segment.file_pos + buffer.len() as u64 {
+                return Ok(false);
+            }
+            file.seek(SeekFrom::Start(segment.file_pos)).await.context("failed to seek file for read")?;
+            file.read_exact(buffer).await.context("failed to read file")?;
+        }
+        Ok(true)
+    }
+
+    async fn write_at(&self, pos: u64, data: &[u8]) -> anyhow::Result<()> {
+        for segment in file_segments(&self.files, pos, data.len() as u64) {
+            if let Some(part_file) = self.skipped_part_file(segment.file_index) {
+                let part_pos = self.files[segment.file_index].start_pos as u64 + segment.file_pos;
+                part_file.write_at(part_pos, &data[segment.data_range]).await?;
+                continue;
+            }
+            let mut file = self.lock_file(segment.file_index, true).await?.expect("file should be created");
+            file.seek(SeekFrom::Start(segment.file_pos)).await.context("failed to seek file for write")?;
+            // write_all fails on short writes, instead of silently dropping the rest of the data
+            file.write_all(&data[segment.data_range]).await.context("failed to write data to file")?;
+        }
+        Ok(())
+    }
+
+    fn skipped_part_file(&self, file_index: usize) -> Option<&PartFile> {
+        self.part_file.as_ref().filter(|_| self.skipped[file_index])
+    }
+
+    /// The first and the last pieces of the file, they also have the data of the neighbouring files
+    fn boundary_pieces(&self, info: &FileInfo) -> Vec<&PieceInfo> {
+        let Some(piece_length) = self.pieces.first().map(|piece| piece.length as u64) else {
+            return vec![];
+        };
+        if info.length == 0 {
+            return vec![];
+        }
+        let first = (info.start_pos as u64 / piece_length) as usize;
+        let last = ((info.end_pos() - 1) / piece_length) as usize;
+        let mut indexes = vec![first, last];
+        indexes.dedup();
+        indexes.iter().filter_map(|index| self.pieces.get(*index)).collect()
+    }
+
+    /// Space taken by the data of the skipped files in the part file
+    fn part_file_length(&self) -> u64 {
+        let mut length = 0;
+        for (info, _) in self.files.iter().zip(&self.skipped).filter(|(_, skipped)| **skipped) {
+            for piece in self.boundary_pieces(info) {
+                let (start, end) = overlap(info, piece);
+                length += end - start;
+            }
+        }
+        length
+    }
+
+    /// Copies the data of the first and the last pieces of the files that are not skipped anymore
+    /// from the part file. The files that already exist are not touched. The part file is sparse,
+    /// so only the pieces that match their hashes are copied, the rest are downloaded again
+    async fn merge_part_file(&self) -> anyhow::Result<()> {
+        let mut merged = vec![false; self.files.len()];
+        for (file_index, info) in self.files.iter().enumerate() {
+            merged[file_index] = !self.skipped[file_index] && info.length > 0 && !self.handles[file_index].lock().await.path.exists();
+        }
+        // the data of the merged files is still in the part file, as it was when they were skipped
+        let in_part_file = merged.iter().zip(&self.skipped).map(|(merged, skipped)| *merged || *skipped).collect::<Vec<_>>();
+        for (info, _) in self.files.iter().zip(&merged).filter(|(_, merged)| **merged) {
+            for piece in self.boundary_pieces(info) {
+                let mut data = vec![0; piece.length as usize];
+                if !self.read_at_with(piece.file_start_pos as u64, &mut data, &in_part_file).await? {
+                    continue;
+                }
+                let (start, end) = overlap(info, piece);
+                let piece_start = piece.file_start_pos as u64;
+                let file_data = data[(start - piece_start) as usize..(end - piece_start) as usize].to_vec();
+                if HashPool::global().digest(data).await? == piece.hash {
+                    self.write_at(start, &file_data).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes all dirty pieces to the files, adjacent pieces are written at once
+    async fn write_back(&self) -> anyhow::Result<()> {
+        let _write_back = self.write_back_lock.lock().await;
+        let runs = self.cache.lock().expect("poisoned lock").dirty_runs();
+        for run in &runs {
+            self.write_at(run.pos, &run.data).await?;
+        }
+        self.cache.lock().expect("poisoned lock").mark_written(&runs);
+        Ok(())
+    }
+
+    /// Returns None if some of the piece data is not present on disk
+    async fn read_piece(&self, piece: &PieceInfo) -> anyhow::Result<Option<Arc<Vec<u8>>>> {
+        let pos = piece.file_start_pos as u64;
+        if let Some(data) = self.cache.lock().expect("poisoned lock").get(pos, piece.length) {
+            return Ok(Some(data));
+        }
+        let mut data = vec![0; piece.length as usize];
+        if !self.read_at(pos, &mut data).await? {
+            return Ok(None);
+        }
+        Ok(Some(Arc::new(data)))
+    }
+}
+
+impl Storage for FileStorage {
+    /// Existing data is kept, so that the interrupted downloads could be resumed
+    async fn preallocate(&self) -> anyhow::Result<()> {
+        let mut wanted_files = self.files
+            .iter()
+            .zip(&self.skipped)
+            .filter(|(_, skipped)| !**skipped)
+            .map(|(info, _)| info.clone())
+            .collect::<Vec<_>>();
+        if let Some(part_file) = &self.part_file {
+            self.merge_part_file().await?;
+            if self.skipped.contains(&true) {
+                part_file.create().await?;
+                // the part file is sparse, it only takes the space of the boundary pieces
+                let length = self.part_file_length().try_into().context("part file is too large")?;
+                wanted_files.push(FileInfo{ path: part_file.path().to_path_buf(), length, start_pos: 0 });
+            } else {
+                part_file.remove().await?;
+            }
+        }
+        check_free_space(&wanted_files)?;
+        if self.preallocation == PreallocationMode::None {
+            return Ok(());
+        }
+        for (file_index, info) in self.files.iter().enumerate() {
+            if self.skipped[file_index] {
+                continue;
+            }
+            let file = self.lock_file(file_index, true).await?.expect("file should be created");
+            let file = file.try_clone().await.context("failed to clone file handle")?.into_std().await;
+            let (length, mode) = (info.length as u64, self.preallocation);
+            tokio::task::spawn_blocking(move || preallocate_file(&file, length, mode))
+                .await
+                .context("join error")??;
+        }
+        Ok(())
+    }
+
+    /// The whole piece is read and cached, because peers usually request all blocks of the piece one by one
+    async fn read_block(&self, piece: &PieceInfo, begin: u32, length: u32) -> anyhow::Result<Vec<u8>> {
+        check_block_range(piece, begin, length)?;
+        let Some(data) = self.read_piece(piece).await? else {
+            bail!("data of piece {} is not present on disk", piece.index);
+        };
+        self.cache.lock().expect("poisoned lock").insert_clean(piece.file_start_pos as u64, data.clone());
+        Ok(data[begin as usize..(begin + length) as usize].to_vec())
+    }
+
+    async fn write_piece(&self, piece: &PieceInfo, data: &[u8]) -> anyhow::Result<()> {
+        check_piece_data(piece, data)?;
+        let cache_full = self.cache.lock().expect("poisoned lock").insert_dirty(piece.file_start_pos as u64, data.to_vec());
+        if cache_full {
+            self.write_back().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> anyhow::Result<()> {
+        self.write_back().await?;
+        for handle in &self.handles {
+            if let Some((file, true)) = &mut handle.lock().await.file {
+                file.flush().await.context("failed to flush file")?;
+                file.sync_data().await.context("failed to sync file")?;
+            }
+        }
+        if let Some(part_file) = &self.part_file {
+            part_file.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn hash_piece(&self, piece: &PieceInfo) -> anyhow::Result<Option<[u8; HASH_RAW_LENGTH]>> {
+        let Some(data) = self.read_piece(piece).await? else {
+            return Ok(None);
+        };
+        // the data is only shared if it's cached
+        let data = Arc::try_unwrap(data).unwrap_or_else(|data| data.to_vec());
+        Ok(Some(HashPool::global().digest(data).await?))
+    }
+}
+
+/// Data is kept in memory, nothing is persisted between the runs
+pub(crate) struct MemoryStorage {
+    length: usize,
+    data: std::sync::Mutex<Vec<u8>>,
+}
+impl MemoryStorage {
+    pub fn new(length: u32) -> Self {
+        Self {
+            length: length as usize,
+            data: std::sync::Mutex::new(vec![]),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_data(data: Vec<u8>) -> Self {
+        Self {
+            length: data.len(),
+            data: std::sync::Mutex::new(data),
+        }
+    }
+
+    /// Takes the data out of the storage, it should not be used after this
+    pub fn take_data(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.data.lock().expect("poisoned lock"))
+    }
+
+    /// Returns None if the range was not written yet
+    fn read_range(&self, start: usize, length: usize) -> Option<Vec<u8>> {
+        let data = self.data.lock().expect("poisoned lock");
+        data.get(start..start + length).map(<[u8]>::to_vec)
+    }
+}
+
+impl Storage for MemoryStorage {
+    async fn preallocate(&self) -> anyhow::Result<()> {
+        self.data.lock().expect("poisoned lock").resize(self.length, 0);
+        Ok(())
+    }
+
+    async fn read_block(&self, piece: &PieceInfo, begin: u32, length: u32) -> anyhow::Result<Vec<u8>> {
+        check_block_range(piece, begin, length)?;
+        let Some(block) = self.read_range(piece.file_start_pos as usize + begin as usize, length as usize) else {
+            bail!("data of piece {} is not stored", piece.index);
+        };
+        Ok(block)
+    }
+
+    async fn write_piece(&self, piece: &PieceInfo, data: &[u8]) -> anyhow::Result<()> {
+        check_piece_data(piece, data)?;
+        let start = piece.file_start_pos as usize;
+        let end = start + data.len();
+        if end > self.length {
+            bail!("piece {} ends at {end}, but the storage length is {}", piece.index, self.length);
+        }
+        let mut stored = self.data.lock().expect("poisoned lock");
+        if stored.len() < end {
+            stored.resize(end, 0);
+        }
+        stored[start..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    async fn flush(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn hash_piece(&self, piece: &PieceInfo) -> anyhow::Result<Option<[u8; HASH_RAW_LENGTH]>> {
+        let Some(data) = self.read_range(piece.file_start_pos as usize, piece.length as usize) else {
+            return Ok(None);
+        };
+        Ok(Some(HashPool::global().digest(data).await?))
+    }
+}
+
+/// Every piece is kept in a separate file in the directory, so that the pieces could be assembled later
+pub(crate) struct PieceDirStorage {
+    dir: PathBuf,
+    /// pieces that were written, but not synced yet
+    unsynced: std::sync::Mutex<Vec<u32>>,
+}
+impl PieceDirStorage {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            unsynced: std::sync::Mutex::new(vec![]),
+        }
+    }
+
+    fn piece_path(&self, index: u32) -> PathBuf {
+        self.dir.join(format!("{index}.piece"))
+    }
+
+    /// Returns None if the piece file does not exist, or has an invalid length
+    async fn read_piece(&self, piece: &PieceInfo) -> anyhow::Result<Option<Vec<u8>>> {
+        let path = self.piece_path(piece.index);
+        let data = match tokio::fs::read(&path).await {
+            Ok(data) => data,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error).context(format!("failed to read {}", path.display())),
+        };
+        if data.len() != piece.length as usize {
+            return Ok(None);
+        }
+        Ok(Some(data))
+    }
+}
+
+impl Storage for PieceDirStorage {
+    async fn preallocate(&self) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await.context(format!("failed to create directory {}", self.dir.display()))
+    }
+
+    async fn read_block(&self, piece: &PieceInfo, begin: u32, length: u32) -> anyhow::Result<Vec<u8>> {
+        check_block_range(piece, begin, length)?;
+        let Some(data) = self.read_piece(piece).await? else {
+            bail!("piece {} is not stored", piece.index);
+        };
+        Ok(data[begin as usize..(begin + length) as usize].to_vec())
+    }
+
+    async fn write_piece(&self, piece: &PieceInfo, data: &[u8]) -> anyhow::Result<()> {
+        check_piece_data(piece, data)?;
+        let path = self.piece_path(piece.index);
+        // written into a temporary file first, so that a partially written piece is never found by its name
+        let mut temp_path = path.clone().into_os_string();
+        temp_path.push(".tmp");
+        tokio::fs::write(&temp_path, data).await.context("failed to write piece file")?;
+        tokio::fs::rename(&temp_path, &path).await.context("failed to rename piece file")?;
+        self.unsynced.lock().expect("poisoned lock").push(piece.index);
+        Ok(())
+    }
+
+    async fn flush(&self) -> anyhow::Result<()> {
+        let unsynced = std::mem::take(&mut *self.unsynced.lock().expect("poisoned lock"));
+        for index in unsynced {
+            let file = File::open(self.piece_path(index)).await.context("failed to open piece file")?;
+            file.sync_all().await.context("failed to sync piece file")?;
+        }
+        Ok(())
+    }
+
+    async fn hash_piece(&self, piece: &PieceInfo) -> anyhow::Result<Option<[u8; HASH_RAW_LENGTH]>> {
+        let Some(data) = self.read_piece(piece).await? else {
+            return Ok(None);
+        };
+        Ok(Some(HashPool::global().digest(data).await?))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::path::Path;
+    use sha1::{Digest, Sha1};
+    use crate::seed::test::get_data;
+    use crate::torrent::TorrentInfo;
+    use super::*;
+
+    const PIECE_LENGTH: u32 = 100;
+
+    pub(crate) fn create_multi_file_info(data: &[u8]) -> TorrentInfo {
+        let pieces = data.chunks(PIECE_LENGTH as usize).map(|piece| Sha1::digest(piece).into()).collect();
+        let files = [("a", 150), ("dir/b", 0), ("dir/c", 110)];
+        TorrentInfo::new_multi_file("test", &files, PIECE_LENGTH, pieces)
+    }
+
+    /// Writes all pieces of the data, and checks that they can be read back
+    pub(crate) async fn check_storage(storage: impl Storage, info: &TorrentInfo, data: &[u8], empty_is_missing: bool) -> anyhow::Result<()> {
+        let pieces = info.get_all_pieces_info().collect::<Vec<_>>();
+        if empty_is_missing {
+            assert_eq!(None, storage.hash_piece(&pieces[1]).await?, "piece should not be stored yet");
+        }
+        storage.preallocate().await?;
+        for piece in &pieces {
+            let start = piece.file_start_pos as usize;
+            storage.write_piece(piece, &data[start..start + piece.length as usize]).await?;
+        }
+        storage.flush().await?;
+
+        for piece in &pieces {
+            assert_eq!(Some(piece.hash), storage.hash_piece(piece).await?);
+        }
+        let block = storage.read_block(&pieces[1], 40, 20).await?;
+        assert_eq!(&data[140..160], &block[..], "block should span multiple files");
+        assert!(storage.read_block(&pieces[2], 50, 20).await.is_err(), "block should not go past the end of the piece");
+        assert!(storage.write_piece(&pieces[0], &data[..10]).await.is_err(), "partial piece should not be written");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_storage() -> anyhow::Result<()> {
+        let data = get_data(260);
+        let info = create_multi_file_info(&data);
+        let dir = tempfile::tempdir()?;
+        let storage = FileStorage::new(info.get_files_info(dir.path()));
+        check_storage(storage, &info, &data, true).await?;
+
+        assert_eq!(&data[..150], &std::fs::read(dir.path().join("a"))?[..]);
+        assert!(std::fs::read(dir.path().join("dir/b"))?.is_empty());
+        assert_eq!(&data[150..], &std::fs::read(dir.path().join("dir/c"))?[..]);
+
+        let storage = FileStorage::new(info.get_files_info(Path::new("/nonexistent")));
+        let piece = info.get_piece_info(0)?;
+        assert_eq!(None, storage.hash_piece(&piece).await?, "missing files should not be created on read");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_preallocation_modes() -> anyhow::Result<()> {
+        let data = get_data(260);
+        let info = create_multi_file_info(&data);
+        let dir = tempfile::tempdir()?;
+        let storage = FileStorage::new(info.get_files_info(dir.path())).with_preallocation(PreallocationMode::None);
+        storage.preallocate().await?;
+        assert!(!dir.path().join("a").exists(), "files should not be created up front");
+        check_storage(storage, &info, &data, true).await?;
+
+        let dir = tempfile::tempdir()?;
+        let storage = FileStorage::new(info.get_files_info(dir.path())).with_preallocation(PreallocationMode::Full);
+        storage.preallocate().await?;
+        assert_eq!(110, std::fs::metadata(dir.path().join("dir/c"))?.len());
+        assert_eq!(0, std::fs::metadata(dir.path().join("dir/b"))?.len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_cache() -> anyhow::Result<()> {
+        let data = get_data(260);
+        let info = create_multi_file_info(&data);
+        let pieces = info.get_all_pieces_info().collect::<Vec<_>>();
+        let dir = tempfile::tempdir()?;
+        let storage = FileStorage::with_cache_size(info.get_files_info(dir.path()), 200);
+        storage.write_piece(&pieces[1], &data[100..200]).await?;
+        storage.write_piece(&pieces[0], &data[..100]).await?;
+        assert!(!dir.path().join("a").exists(), "pieces should stay in the cache");
+        storage.write_piece(&pieces[2], &data[200..]).await?;
+        assert_eq!(&data[..150], &std::fs::read(dir.path().join("a"))?[..], "cache should be written back when it is full");
+        let stats = storage.cache_stats();
+        assert_eq!((1, 1, 3), (stats.flushes, stats.disk_writes, stats.written_pieces), "adjacent pieces should be written at once");
+
+        storage.read_block(&pieces[1], 0, 10).await?;
+        let block = storage.read_block(&pieces[1], 10, 10).await?;
+        assert_eq!(&data[110..120], &block[..]);
+        assert!(storage.cache_stats().hits >= 1, "blocks of the same piece should be read from the cache");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_move() -> anyhow::Result<()> {
+        let data = get_data(260);
+        let info = create_multi_file_info(&data);
+        let pieces = info.get_all_pieces_info().collect::<Vec<_>>();
+        let dir = tempfile::tempdir()?;
+        let storage = FileStorage::new(info.get_files_info(&dir.path().join("staging")));
+        storage.write_piece(&pieces[0], &data[..100]).await?;
+        storage.write_piece(&pieces[1], &data[100..200]).await?;
+
+        let new_paths = info.get_files_info(&dir.path().join("final")).into_iter().map(|file| file.path).collect();
+        storage.move_files(new_paths).await?;
+        assert!(!dir.path().join("staging/a").exists(), "files should be moved");
+        assert_eq!(&data[..150], &std::fs::read(dir.path().join("final/a"))?[..]);
+        assert!(dir.path().join("final/dir/b").exists(), "empty file should be created");
+
+        storage.write_piece(&pieces[2], &data[200..]).await?;
+        assert_eq!(&data[150..160], &storage.read_block(&pieces[1], 50, 10).await?[..]);
+        storage.flush().await?;
+        assert_eq!(&data[150..], &std::fs::read(dir.path().join("final/dir/c"))?[..], "files should be written at the new paths");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_part_file() -> anyhow::Result<()> {
+        let data = get_data(260);
+        let info = create_multi_file_info(&data);
+        let pieces = info.get_all_pieces_info().collect::<Vec<_>>();
+        let dir = tempfile::tempdir()?;
+        let part_path = dir.path().join(".parts");
+        let storage = FileStorage::new(info.get_files_info(dir.path()))
+            .with_part_file(PartFile::new(part_path.clone()), vec![false, false, true], pieces.clone());
+        storage.preallocate().await?;
+        storage.write_piece(&pieces[0], &data[..100]).await?;
+        storage.write_piece(&pieces[1], &data[100..200]).await?;
+        storage.flush().await?;
+        assert!(!dir.path().join("dir/c").exists(), "skipped file should not be created");
+        assert_eq!(Some(pieces[1].hash), storage.hash_piece(&pieces[1]).await?, "boundary piece should be verified");
+        assert_eq!(&data[150..200], &std::fs::read(&part_path)?[150..200]);
+        drop(storage);
+        // the last piece was not downloaded, the part file has a hole in its place
+        PartFile::new(part_path.clone()).write_at(259, &data[259..]).await?;
+
+        let storage = FileStorage::new(info.get_files_info(dir.path()))
+            .with_preallocation(PreallocationMode::None)
+            .with_part_file(PartFile::new(part_path.clone()), vec![false; 3], pieces.clone());
+        storage.preallocate().await?;
+        assert!(!part_path.exists(), "part file should be removed when nothing is skipped");
+        assert_eq!(&data[150..200], &std::fs::read(dir.path().join("dir/c"))?[..], "only the verified pieces should be merged");
+        assert_eq!(Some(pieces[1].hash), storage.hash_piece(&pieces[1]).await?, "boundary piece should be kept");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_memory_storage() -> anyhow::Result<()> {
+        let data = get_data(260);
+        let info = create_multi_file_info(&data);
+        check_storage(MemoryStorage::new(data.len() as u32), &info, &data, true).await
+    }
+
+    #[tokio::test]
+    async fn test_piece_dir_storage() -> anyhow::Result<()> {
+        let data = get_data(260);
+        let info = create_multi_file_info(&data);
+        let dir = tempfile::tempdir()?;
+        let pieces_dir = dir.path().join("pieces");
+        check_storage(PieceDirStorage::new(pieces_dir.clone()), &info, &data, true).await?;
+        assert_eq!(&data[200..], &std::fs::read(pieces_dir.join("2.piece"))?[..]);
+        Ok(())
+    }
+}
@@ -1,10 +1,16 @@
+use std::borrow::Cow;
 use std::cmp;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use anyhow::{bail, Context};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
+use crate::custom_bdecode::{decode_value_with_options, from_bytes, raw_dict_value, DecodeMode, DecodeOptions};
+use crate::custom_bencode::to_bytes;
+use crate::sanitize::sanitize_path;
 
 pub(crate) const HASH_RAW_LENGTH: usize = 20;
 
@@ -13,15 +19,23 @@ pub(crate) struct Torrent {
     pub announce: String,
     pub info: TorrentInfo,
 }
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
 pub(crate) struct TorrentInfo {
-    pub name: String,
+    /// can be in a legacy encoding, it's not required to be a valid utf-8
+    name: ByteBuf,
+    /// some clients put the name in the original encoding into the name, and the utf-8 one here
+    #[serde(rename = "name.utf-8", default, skip_serializing_if = "Option::is_none")]
+    name_utf8: Option<String>,
     #[serde(flatten)]
     torrent_type: TorrentType,
     #[serde(rename = "piece length")]
     pub piece_length: u32,
     #[serde(deserialize_with = "deserialize_pieces", serialize_with = "serialize_pieces")]
     pub pieces: Vec<[u8; HASH_RAW_LENGTH]>,
+    /// hash of the info dict as it is in the torrent file, with the keys that are not parsed and in the original encoding.
+    /// Set by `parse_torrent`, the info that is created in the code is encoded to get the hash
+    #[serde(skip)]
+    raw_info_hash: Option<[u8; HASH_RAW_LENGTH]>,
 }
 
 fn deserialize_pieces<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<[u8; HASH_RAW_LENGTH]>, D::Error> {
@@ -42,24 +56,83 @@ fn serialize_pieces<S: Serializer>(v: &Vec<[u8; HASH_RAW_LENGTH]>, ser: S) -> Re
     ser.serialize_bytes(&bytes)
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
 #[serde(untagged)]
 pub(crate) enum TorrentType {
     SingleFile{
         length: u32,
     },
     MultiFile{
-        files: TorrentFile,
+        files: Vec<TorrentFile>,
     },
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
 pub(crate) struct TorrentFile {
-    length: usize,
-    path: String,
+    length: u32,
+    /// path components, relative to the torrent directory. Like the name, they can be in a legacy encoding
+    path: Vec<ByteBuf>,
+    #[serde(rename = "path.utf-8", default, skip_serializing_if = "Option::is_none")]
+    path_utf8: Option<Vec<String>>,
+}
+impl TorrentFile {
+    /// Path components are untrusted, they should be sanitized before they are used
+    fn path_components(&self) -> Vec<Cow<'_, str>> {
+        match &self.path_utf8 {
+            Some(path) => path.iter().map(|component| Cow::Borrowed(component.as_str())).collect(),
+            None => self.path.iter().map(|component| String::from_utf8_lossy(component)).collect(),
+        }
+    }
+}
+
+/// A file of the torrent on disk, positioned in the concatenated data of all files
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct FileInfo {
+    pub path: PathBuf,
+    pub length: u32,
+    pub start_pos: u32,
+}
+impl FileInfo {
+    pub fn end_pos(&self) -> u64 {
+        self.start_pos as u64 + self.length as u64
+    }
 }
 
+/// Part of a data range that is stored in a single file
 #[derive(Debug, PartialEq)]
+pub(crate) struct FileSegment {
+    pub file_index: usize,
+    /// position of the segment in the file
+    pub file_pos: u64,
+    /// position of the segment in the data range
+    pub data_range: Range<usize>,
+}
+
+/// Splits the data range between the files that it overlaps, empty files are skipped
+pub(crate) fn file_segments(files: &[FileInfo], start: u64, length: u64) -> Vec<FileSegment> {
+    let end = start + length;
+    let first_file = files.partition_point(|file| file.end_pos() <= start);
+    let mut segments = vec![];
+    for (file_index, file) in files.iter().enumerate().skip(first_file) {
+        let file_start = file.start_pos as u64;
+        if file_start >= end {
+            break;
+        }
+        let from = cmp::max(start, file_start);
+        let to = cmp::min(end, file.end_pos());
+        if from >= to {
+            continue;
+        }
+        segments.push(FileSegment {
+            file_index,
+            file_pos: from - file_start,
+            data_range: (from - start) as usize..(to - start) as usize,
+        });
+    }
+    segments
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub(crate) struct PieceInfo {
     pub index: u32,
     pub length: u32,
@@ -68,32 +141,92 @@ pub(crate) struct PieceInfo {
 }
 
 impl TorrentInfo {
+    #[cfg(test)]
+    pub fn new_single_file(name: &str, length: u32, piece_length: u32, pieces: Vec<[u8; HASH_RAW_LENGTH]>) -> Self {
+        Self {
+            name: ByteBuf::from(name.as_bytes()),
+            name_utf8: None,
+            torrent_type: TorrentType::SingleFile { length },
+            piece_length,
+            pieces,
+            raw_info_hash: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn new_multi_file(name: &str, files: &[(&str, u32)], piece_length: u32, pieces: Vec<[u8; HASH_RAW_LENGTH]>) -> Self {
+        let files = files
+            .iter()
+            .map(|(path, length)| TorrentFile {
+                length: *length,
+                path: path.split('/').map(|component| ByteBuf::from(component.as_bytes())).collect(),
+                path_utf8: None,
+            })
+            .collect();
+        Self {
+            name: ByteBuf::from(name.as_bytes()),
+            name_utf8: None,
+            torrent_type: TorrentType::MultiFile { files },
+            piece_length,
+            pieces,
+            raw_info_hash: None,
+        }
+    }
+
     pub fn get_info_hash(&self) -> anyhow::Result<[u8; 20]> {
-        let info_encoded = serde_bencode::to_bytes(self).context("failed to encode info")?;
+        if let Some(info_hash) = self.raw_info_hash {
+            return Ok(info_hash);
+        }
+        let info_encoded = to_bytes(self).context("failed to encode info")?;
         let mut hasher = Sha1::new();
         hasher.update(info_encoded);
         let output = hasher.finalize();
-        let output = output.try_into().context("failed to convert hash to slice")?;
-        Ok(output)
+        Ok(output.into())
     }
 
     pub fn get_encoded_piece_hashes(&self) -> impl Iterator<Item = String> + '_ {
         self.pieces
             .iter()
-            .map(|hash| hex::encode(hash))
+            .map(hex::encode)
     }
 
     pub fn get_length(&self) -> u32 {
-        match self.torrent_type {
-            TorrentType::SingleFile { length } => length,
-            TorrentType::MultiFile { .. } => todo!("multi file is not implemented yet")
+        match &self.torrent_type {
+            TorrentType::SingleFile { length } => *length,
+            TorrentType::MultiFile { files } => files.iter().map(|file| file.length).sum(),
         }
     }
 
-    pub fn is_single_file(&self) -> bool {
-        match self.torrent_type {
-            TorrentType::SingleFile { .. } => true,
-            _ => false,
+    /// Name that is safe to use as a file name, it's the default save location
+    pub fn safe_name(&self) -> PathBuf {
+        let name = match &self.name_utf8 {
+            Some(name) => Cow::Borrowed(name.as_str()),
+            None => String::from_utf8_lossy(&self.name),
+        };
+        sanitize_path(&[name])
+    }
+
+    /// Data path is the file itself for single file torrents, and the torrent directory for multi file ones.
+    /// Paths of the files are sanitized, so they are always inside the torrent directory
+    pub fn get_files_info(&self, data_path: &Path) -> Vec<FileInfo> {
+        match &self.torrent_type {
+            TorrentType::SingleFile { length } => vec![FileInfo {
+                path: data_path.to_path_buf(),
+                length: *length,
+                start_pos: 0,
+            }],
+            TorrentType::MultiFile { files } => {
+                let mut start_pos = 0;
+                files.iter().map(|file| {
+                    let info = FileInfo {
+                        path: data_path.join(sanitize_path(&file.path_components())),
+                        length: file.length,
+                        start_pos,
+                    };
+                    start_pos += file.length;
+                    info
+                }).collect()
+            },
         }
     }
 
@@ -131,25 +264,55 @@ pub(crate) async fn parse_torrent_from_file(path: &str) -> anyhow::Result<Torren
 }
 
 pub(crate) fn parse_torrent(data: &[u8]) -> anyhow::Result<Torrent> {
-    let torrent: Torrent = serde_bencode::from_bytes(&data).context("failed to decode torrent struct")?;
+    let mut torrent: Torrent = from_bytes(data).context("failed to decode torrent struct")?;
+    // the struct does not keep the unknown keys and the original encoding, so the hash is taken from the input
+    let info = raw_dict_value(data, b"info")?.context("torrent has no info dict")?;
+    torrent.info.raw_info_hash = Some(Sha1::digest(info).into());
+    // other clients may re-encode the info dict, and get a different hash
+    if let Err(error) = decode_value_with_options(info, DecodeOptions{ mode: DecodeMode::Strict, ..Default::default() }) {
+        eprintln!("warning: info dict is not canonically encoded, the info hash may not match some clients: {error:#}");
+    }
     let info = &torrent.info;
     let piece_length = info.piece_length;
 
+    if let TorrentType::MultiFile { files } = &info.torrent_type {
+        let total_length = files.iter().map(|file| file.length as u64).sum::<u64>();
+        if total_length > u32::MAX as u64 {
+            bail!("total length {total_length} of all files is too large");
+        }
+        check_file_paths(info)?;
+    }
     let length = info.get_length();
     if piece_length > length {
         bail!("piece length {piece_length} is larger than total length {length}");
     }
-    let expected_piece_count = (length + piece_length - 1) / piece_length; // integer division with a ceil
+    let expected_piece_count = length.div_ceil(piece_length); // integer division with a ceil
 
     if info.pieces.len() != (expected_piece_count as usize) {
         bail!("count of hashes {} does not match the count that is based on the piece length {expected_piece_count}", info.pieces.len());
     }
-    if info.pieces.len() == 0 {
+    if info.pieces.is_empty() {
         bail!("torrent has no pieces!");
     }
     Ok(torrent)
 }
 
+/// Different paths in the torrent can become the same after the sanitisation, and a file can not be a directory of another file
+fn check_file_paths(info: &TorrentInfo) -> anyhow::Result<()> {
+    let mut paths = info
+        .get_files_info(Path::new(""))
+        .into_iter()
+        .map(|file| file.path)
+        .collect::<Vec<_>>();
+    paths.sort();
+    for pair in paths.windows(2) {
+        if pair[1].starts_with(&pair[0]) {
+            bail!("file path {} conflicts with {}", pair[1].display(), pair[0].display());
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -157,12 +320,14 @@ mod test {
     #[test]
     fn test_get_piece_info() {
         let info = TorrentInfo{
-            name: "test".to_string(),
+            name: ByteBuf::from(b"test".as_slice()),
+            name_utf8: None,
             torrent_type: TorrentType::SingleFile {
                 length: 100,
             },
             piece_length: 100,
             pieces: vec![get_hash(1)],
+            raw_info_hash: None,
         };
         let piece_info = info.get_piece_info(0).expect("piece 0 should exist");
         assert_eq!(PieceInfo{index: 0, length: 100, hash: get_hash(1), file_start_pos: 0}, piece_info);
@@ -170,12 +335,14 @@ mod test {
         assert!(piece_info.is_err(), "piece 1 should not exist");
 
         let info = TorrentInfo{
-            name: "test".to_string(),
+            name: ByteBuf::from(b"test".as_slice()),
+            name_utf8: None,
             torrent_type: TorrentType::SingleFile {
                 length: 101,
             },
             piece_length: 100,
             pieces: vec![get_hash(1), get_hash(2)],
+            raw_info_hash: None,
         };
         let piece_info = info.get_piece_info(0).expect("piece 0 should exist");
         assert_eq!(PieceInfo{index: 0, length: 100, hash: get_hash(1), file_start_pos: 0}, piece_info);
@@ -185,8 +352,133 @@ mod test {
         assert!(piece_info.is_err(), "piece 2 should not exist");
     }
 
+    #[test]
+    fn test_get_files_info() {
+        let info = TorrentInfo::new_multi_file("test", &[("a.txt", 100), ("dir/b.txt", 0), ("dir/c.txt", 50)], 100, vec![get_hash(1), get_hash(2)]);
+        assert_eq!(150, info.get_length());
+        let expected = vec![
+            FileInfo{ path: PathBuf::from("data/a.txt"), length: 100, start_pos: 0 },
+            FileInfo{ path: PathBuf::from("data/dir/b.txt"), length: 0, start_pos: 100 },
+            FileInfo{ path: PathBuf::from("data/dir/c.txt"), length: 50, start_pos: 100 },
+        ];
+        assert_eq!(expected, info.get_files_info(Path::new("data")));
+
+        let info = TorrentInfo::new_single_file("test", 100, 100, vec![get_hash(1)]);
+        let expected = vec![FileInfo{ path: PathBuf::from("data"), length: 100, start_pos: 0 }];
+        assert_eq!(expected, info.get_files_info(Path::new("data")));
+    }
+
+    #[test]
+    fn test_file_segments() {
+        let info = TorrentInfo::new_multi_file("test", &[("a", 100), ("b", 0), ("c", 50), ("d", 10)], 100, vec![get_hash(1), get_hash(2)]);
+        let files = info.get_files_info(Path::new("data"));
+        let expected = vec![FileSegment{ file_index: 0, file_pos: 0, data_range: 0..100 }];
+        assert_eq!(expected, file_segments(&files, 0, 100));
+        let expected = vec![
+            FileSegment{ file_index: 0, file_pos: 90, data_range: 0..10 },
+            FileSegment{ file_index: 2, file_pos: 0, data_range: 10..60 },
+            FileSegment{ file_index: 3, file_pos: 0, data_range: 60..65 },
+        ];
+        assert_eq!(expected, file_segments(&files, 90, 65));
+        assert!(file_segments(&files, 160, 10).is_empty(), "range after the end should have no segments");
+    }
+
+    #[test]
+    fn test_parse_multi_file() -> anyhow::Result<()> {
+        let data = b"d8:announce3:url4:infod5:filesld6:lengthi3e4:pathl1:a1:beed6:lengthi2e4:pathl1:ceee4:name4:test12:piece lengthi4e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbee";
+        let torrent = parse_torrent(data)?;
+        assert_eq!(5, torrent.info.get_length());
+        let files = torrent.info.get_files_info(Path::new("data"));
+        assert_eq!(PathBuf::from("data/a/b"), files[0].path);
+        assert_eq!(PathBuf::from("data/c"), files[1].path);
+        Ok(())
+    }
+
+    /// Every file is 1 byte long, and the piece length is 1
+    fn encode_multi_file_info(paths: &[&[&str]], utf8_paths: Option<&[&[&str]]>) -> Vec<u8> {
+        let encode_path = |path: &[&str]| path.iter().map(|part| format!("{}:{part}", part.len())).collect::<String>();
+        let mut files = String::new();
+        for (file_no, path) in paths.iter().enumerate() {
+            files += &format!("d6:lengthi1e4:pathl{}e", encode_path(path));
+            if let Some(utf8_paths) = utf8_paths {
+                files += &format!("10:path.utf-8l{}e", encode_path(utf8_paths[file_no]));
+            }
+            files += "e";
+        }
+        let pieces = "a".repeat(HASH_RAW_LENGTH * paths.len());
+        format!("d5:filesl{files}e4:name4:test12:piece lengthi1e6:pieces{}:{pieces}e", pieces.len()).into_bytes()
+    }
+
+    fn parse_info(info: &[u8]) -> anyhow::Result<Torrent> {
+        parse_torrent(&[b"d8:announce3:url4:info", info, b"e"].concat())
+    }
+
+    #[test]
+    fn test_hostile_paths() -> anyhow::Result<()> {
+        let info = encode_multi_file_info(&[&["..", "..", "etc", "passwd"], &["/root", "x"], &["", "c:", "NUL.txt"]], None);
+        let torrent = parse_info(&info)?;
+        let data_path = Path::new("data");
+        let paths = torrent.info.get_files_info(data_path).into_iter().map(|file| file.path).collect::<Vec<_>>();
+        assert_eq!(vec![PathBuf::from("data/etc/passwd"), PathBuf::from("data/_root/x"), PathBuf::from("data/c_/_NUL.txt")], paths);
+        assert!(paths.iter().all(|path| path.starts_with(data_path)), "paths should stay inside the data directory");
+
+        let info = encode_multi_file_info(&[&["a"], &["..", "a"]], None);
+        assert!(parse_info(&info).is_err(), "paths that are the same after the sanitisation should be rejected");
+        let info = encode_multi_file_info(&[&["a"], &["a", "b"]], None);
+        assert!(parse_info(&info).is_err(), "file that is also a directory should be rejected");
+        Ok(())
+    }
+
+    #[test]
+    fn test_utf8_paths() -> anyhow::Result<()> {
+        let info = encode_multi_file_info(&[&["cafe"]], Some(&[&["café"]]));
+        let torrent = parse_info(&info)?;
+        assert_eq!(vec![PathBuf::from("data/café")], torrent.info.get_files_info(Path::new("data")).into_iter().map(|file| file.path).collect::<Vec<_>>());
+        let expected_hash: [u8; HASH_RAW_LENGTH] = Sha1::digest(&info).into();
+        assert_eq!(expected_hash, torrent.info.get_info_hash()?, "alternate paths should be kept in the info hash");
+        assert_eq!(PathBuf::from("test"), torrent.info.safe_name());
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_encoding() -> anyhow::Result<()> {
+        // "café" in latin-1, with and without the utf-8 alternates
+        let pieces = "a".repeat(HASH_RAW_LENGTH * 2);
+        let info = [
+            &b"d5:filesl"[..],
+            b"d6:lengthi1e4:pathl4:caf\xe9e10:path.utf-8l5:caf\xc3\xa9ee",
+            b"d6:lengthi1e4:pathl2:\xe9tee",
+            b"e4:name4:caf\xe910:name.utf-85:caf\xc3\xa912:piece lengthi1e6:pieces40:",
+            pieces.as_bytes(),
+            b"e",
+        ].concat();
+        let torrent = parse_info(&info)?;
+        let paths = torrent.info.get_files_info(Path::new("data")).into_iter().map(|file| file.path).collect::<Vec<_>>();
+        assert_eq!(vec![PathBuf::from("data/café"), PathBuf::from("data/\u{fffd}t")], paths);
+        assert_eq!(PathBuf::from("café"), torrent.info.safe_name());
+        let expected_hash: [u8; HASH_RAW_LENGTH] = Sha1::digest(&info).into();
+        assert_eq!(expected_hash, torrent.info.get_info_hash()?, "legacy names should be kept in the info hash");
+
+        let info = [&b"d6:lengthi1e4:name4:caf\xe912:piece lengthi1e6:pieces20:"[..], &pieces.as_bytes()[..HASH_RAW_LENGTH], b"e"].concat();
+        assert_eq!(PathBuf::from("caf\u{fffd}"), parse_info(&info)?.info.safe_name());
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_info_hash() -> anyhow::Result<()> {
+        let pieces = "a".repeat(HASH_RAW_LENGTH);
+        // unknown keys, and keys that are not sorted
+        let info = format!("d4:name4:test6:lengthi1e12:piece lengthi1e6:pieces20:{pieces}7:privatei1e6:source3:abce").into_bytes();
+        let strict = DecodeOptions{ mode: DecodeMode::Strict, ..Default::default() };
+        assert!(decode_value_with_options(&info, strict).is_err(), "info dict should not be canonical, so that the warning is printed");
+        let torrent = parse_info(&info)?;
+        let expected_hash: [u8; HASH_RAW_LENGTH] = Sha1::digest(&info).into();
+        assert_eq!(expected_hash, torrent.info.get_info_hash()?, "info hash should be taken from the original bytes");
+        assert_ne!(expected_hash, TorrentInfo::new_single_file("test", 1, 1, vec![get_hash(b'a')]).get_info_hash()?);
+        Ok(())
+    }
+
     fn get_hash(val: u8) -> [u8; HASH_RAW_LENGTH] {
-        let hash = [val; HASH_RAW_LENGTH];
-        hash
+        [val; HASH_RAW_LENGTH]
     }
 }
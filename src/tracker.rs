@@ -5,12 +5,12 @@ use reqwest::Client;
 use reqwest::Url;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_bytes::ByteBuf;
+use crate::custom_bdecode::from_bytes;
 use crate::torrent::Torrent;
 
 pub(crate) const MY_PEER_ID: &str = "00112233445566778899";
 pub(crate) const PEER_ID_LEN: usize = MY_PEER_ID.len();
 
-const MY_PORT: u16 = 6881;
 const PEER_LENGTH: usize = 6;
 
 #[derive(Serialize)]
@@ -68,21 +68,23 @@ fn deserialize_peers<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<S
     Ok(peers)
 }
 
-pub(crate) async fn request_peers(torrent: &Torrent) -> anyhow::Result<PeersResponse> {
+/// `port` is the port we are accepting connections on,
+/// `left` is the amount of bytes we still need to download, 0 when seeding
+pub(crate) async fn request_peers(torrent: &Torrent, port: u16, left: u32) -> anyhow::Result<PeersResponse> {
     let Torrent{ announce, info } = torrent;
 
     let info_hash = info.get_info_hash()?;
     let query = PeersQueryData {
         info_hash: &info_hash,
         peer_id: MY_PEER_ID,
-        port: MY_PORT,
+        port,
         uploaded: 0,
         downloaded: 0,
-        left: info.get_length(),
+        left,
         compact: true,
     };
     let query_string = serde_qs::to_string(&query)?;
-    let mut url = Url::parse(&announce).context("failed to parse announce url")?;
+    let mut url = Url::parse(announce).context("failed to parse announce url")?;
     url.set_query(Some(&query_string));
 
     let client = Client::builder()
@@ -93,12 +95,12 @@ pub(crate) async fn request_peers(torrent: &Torrent) -> anyhow::Result<PeersResp
 
     let response = request.send().await.context("request failed")?;
     let response = response.bytes().await.context("failed to get response bytes")?;
-    let response = serde_bencode::from_bytes::<PeersResponseType>(&response).context("failed to parse response into structure")?;
+    let response = from_bytes::<PeersResponseType>(&response).context("failed to parse response into structure")?;
     let response = match response {
         PeersResponseType::Success(res) => res,
         PeersResponseType::Fail{reason} => bail!("got error response {reason}"),
     };
-    if response.peers.len() == 0 {
+    if response.peers.is_empty() {
         bail!("torrent has no peers!");
     }
 
@@ -0,0 +1,257 @@
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::Mutex;
+use anyhow::Context;
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use crate::torrent::{file_segments, FileInfo, PieceInfo, TorrentInfo};
+
+/// pieces that are read, but not hashed yet, per hashing thread
+const HASH_QUEUE_PER_THREAD: usize = 2;
+
+#[derive(Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DataStatus {
+    Complete,
+    /// some of the data is not present on disk
+    Missing,
+    /// the data is present, but does not match the torrent
+    Corrupt,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct FileReport {
+    pub path: String,
+    pub length: u32,
+    /// None if the file does not exist
+    pub actual_length: Option<u64>,
+    pub status: DataStatus,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct VerifyReport {
+    pub pieces: Vec<DataStatus>,
+    pub files: Vec<FileReport>,
+}
+impl VerifyReport {
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(|status| *status == DataStatus::Complete)
+            && self.files.iter().all(|file| file.status == DataStatus::Complete)
+    }
+
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        serde_json::to_string_pretty(self).context("failed to encode report")
+    }
+
+    pub fn to_text(&self) -> String {
+        let count = |status| self.pieces.iter().filter(|piece| **piece == status).count();
+        let mut lines = vec![format!(
+            "Pieces: {} complete, {} missing, {} corrupt, {} total",
+            count(DataStatus::Complete),
+            count(DataStatus::Missing),
+            count(DataStatus::Corrupt),
+            self.pieces.len(),
+        )];
+        for (index, status) in self.pieces.iter().enumerate() {
+            if *status != DataStatus::Complete {
+                lines.push(format!("Piece {index}: {}", status_name(*status)));
+            }
+        }
+        for file in &self.files {
+            lines.push(format!("File {}: {}", file.path, status_name(file.status)));
+        }
+        let result = if self.is_complete() { "Data is complete" } else { "Data is incomplete" };
+        lines.push(result.to_string());
+        lines.join("\n")
+    }
+}
+
+fn status_name(status: DataStatus) -> &'static str {
+    match status {
+        DataStatus::Complete => "complete",
+        DataStatus::Missing => "missing",
+        DataStatus::Corrupt => "corrupt",
+    }
+}
+
+/// Reads pieces from the data files, pieces can span multiple files
+struct DataReader {
+    files: Vec<FileInfo>,
+    /// opened files with their actual lengths, None if the file does not exist
+    handles: Vec<Option<(File, u64)>>,
+}
+impl DataReader {
+    fn open(files: Vec<FileInfo>) -> anyhow::Result<Self> {
+        let mut handles = vec![];
+        for info in &files {
+            let handle = match File::open(&info.path) {
+                Ok(file) => {
+                    let length = file.metadata().context(format!("failed to get metadata of {}", info.path.display()))?.len();
+                    Some((file, length))
+                },
+                Err(error) if error.kind() == ErrorKind::NotFound => None,
+                Err(error) => return Err(error).context(format!("failed to open {}", info.path.display())),
+            };
+            handles.push(handle);
+        }
+        Ok(Self{ files, handles })
+    }
+
+    /// Returns None if some of the piece data is not present on disk
+    fn read_piece(&mut self, piece: &PieceInfo) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut data = vec![0; piece.length as usize];
+        for segment in file_segments(&self.files, piece.file_start_pos as u64, piece.length as u64) {
+            let Some((handle, actual_length)) = &mut self.handles[segment.file_index] else {
+                return Ok(None);
+            };
+            let buffer = &mut data[segment.data_range];
+            if *actual_length < segment.file_pos + buffer.len() as u64 {
+                return Ok(None);
+            }
+            let path = self.files[segment.file_index].path.display();
+            handle.seek(SeekFrom::Start(segment.file_pos)).context(format!("failed to seek {path}"))?;
+            handle.read_exact(buffer).context(format!("failed to read {path}"))?;
+        }
+        Ok(Some(data))
+    }
+}
+
+/// Hashes all pieces of the local data. Pieces are read one by one and hashed on all cores, so this blocks
+pub(crate) fn verify_data(info: &TorrentInfo, data_path: &Path) -> anyhow::Result<VerifyReport> {
+    let mut reader = DataReader::open(info.get_files_info(data_path))?;
+    let pieces = Mutex::new(vec![DataStatus::Missing; info.pieces.len()]);
+    let threads_count = std::thread::available_parallelism().map(|count| count.get()).unwrap_or(1);
+    let (sender, receiver) = sync_channel::<(PieceInfo, Vec<u8>)>(threads_count * HASH_QUEUE_PER_THREAD);
+    let receiver = Mutex::new(receiver);
+
+    std::thread::scope(|scope| {
+        for _ in 0..threads_count {
+            scope.spawn(|| loop {
+                let message = receiver.lock().expect("poisoned lock").recv();
+                let Ok((piece, data)) = message else {
+                    break;
+                };
+                let status = if Sha1::digest(&data)[..] == piece.hash {
+                    DataStatus::Complete
+                } else {
+                    DataStatus::Corrupt
+                };
+                pieces.lock().expect("poisoned lock")[piece.index as usize] = status;
+            });
+        }
+        // the sender is dropped when reading is done, which stops the hashing threads
+        read_pieces(info, &mut reader, sender)
+    })?;
+
+    let pieces = pieces.into_inner().expect("poisoned lock");
+    let files = reader.files.into_iter().zip(reader.handles).map(|(file, handle)| {
+        let actual_length = handle.map(|(_, length)| length);
+        let status = file_status(&file, actual_length, info.piece_length, &pieces);
+        FileReport {
+            path: file.path.display().to_string(),
+            length: file.length,
+            actual_length,
+            status,
+        }
+    }).collect();
+    Ok(VerifyReport{ pieces, files })
+}
+
+fn read_pieces(info: &TorrentInfo, reader: &mut DataReader, sender: SyncSender<(PieceInfo, Vec<u8>)>) -> anyhow::Result<()> {
+    for piece in info.get_all_pieces_info() {
+        if let Some(data) = reader.read_piece(&piece)? {
+            sender.send((piece, data)).context("hashing threads have stopped")?;
+        }
+    }
+    Ok(())
+}
+
+fn file_status(file: &FileInfo, actual_length: Option<u64>, piece_length: u32, pieces: &[DataStatus]) -> DataStatus {
+    let Some(actual_length) = actual_length else {
+        return DataStatus::Missing;
+    };
+    if actual_length > file.length as u64 {
+        return DataStatus::Corrupt;
+    }
+    if file.length == 0 {
+        return DataStatus::Complete;
+    }
+    let first_piece = (file.start_pos / piece_length) as usize;
+    let last_piece = ((file.end_pos() - 1) / piece_length as u64) as usize;
+    let file_pieces = &pieces[first_piece..=last_piece];
+    // pieces are shared with the neighbour files, so a missing neighbour also makes this file incomplete
+    if file_pieces.contains(&DataStatus::Corrupt) {
+        DataStatus::Corrupt
+    } else if file_pieces.contains(&DataStatus::Missing) {
+        DataStatus::Missing
+    } else {
+        DataStatus::Complete
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::seed::test::get_data;
+    use crate::torrent::HASH_RAW_LENGTH;
+    use super::*;
+
+    const PIECE_LENGTH: u32 = 100;
+
+    fn get_hashes(data: &[u8]) -> Vec<[u8; HASH_RAW_LENGTH]> {
+        data.chunks(PIECE_LENGTH as usize).map(|piece| Sha1::digest(piece).into()).collect()
+    }
+
+    #[test]
+    fn test_verify_single_file() -> anyhow::Result<()> {
+        let mut data = get_data(PIECE_LENGTH as usize * 3 + 10);
+        let info = TorrentInfo::new_single_file("test", data.len() as u32, PIECE_LENGTH, get_hashes(&data));
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("test");
+
+        let report = verify_data(&info, &path)?;
+        assert_eq!(vec![DataStatus::Missing; 4], report.pieces);
+        assert_eq!(DataStatus::Missing, report.files[0].status);
+
+        data[150] ^= 1;
+        std::fs::write(&path, &data[..250])?;
+        let report = verify_data(&info, &path)?;
+        let expected = vec![DataStatus::Complete, DataStatus::Corrupt, DataStatus::Missing, DataStatus::Missing];
+        assert_eq!(expected, report.pieces);
+        assert_eq!(DataStatus::Corrupt, report.files[0].status);
+        assert_eq!(Some(250), report.files[0].actual_length);
+        assert!(!report.is_complete());
+
+        data[150] ^= 1;
+        std::fs::write(&path, &data)?;
+        let report = verify_data(&info, &path)?;
+        assert!(report.is_complete(), "valid data should be complete");
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_multi_file() -> anyhow::Result<()> {
+        let data = get_data(PIECE_LENGTH as usize * 2 + 50);
+        // a.bin and the start of c.bin share the second piece, b.bin is empty
+        let files = [("a.bin", 120), ("dir/b.bin", 0), ("dir/c.bin", 80), ("d.bin", 50)];
+        let info = TorrentInfo::new_multi_file("test", &files, PIECE_LENGTH, get_hashes(&data));
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("dir"))?;
+        std::fs::write(dir.path().join("a.bin"), &data[..120])?;
+        std::fs::write(dir.path().join("dir/b.bin"), b"")?;
+        std::fs::write(dir.path().join("d.bin"), &data[200..])?;
+
+        let report = verify_data(&info, dir.path())?;
+        assert_eq!(vec![DataStatus::Complete, DataStatus::Missing, DataStatus::Complete], report.pieces);
+        let statuses = report.files.iter().map(|file| file.status).collect::<Vec<_>>();
+        let expected = vec![DataStatus::Missing, DataStatus::Complete, DataStatus::Missing, DataStatus::Complete];
+        assert_eq!(expected, statuses, "file that shares a missing piece should not be complete");
+
+        std::fs::write(dir.path().join("dir/c.bin"), &data[120..200])?;
+        let report = verify_data(&info, dir.path())?;
+        assert!(report.is_complete(), "all files should be complete");
+        assert!(report.to_json()?.contains("\"status\": \"complete\""));
+        Ok(())
+    }
+}